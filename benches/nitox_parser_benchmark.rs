@@ -89,6 +89,7 @@ fn benchmark_parser(c: &mut Criterion) {
                 subject: String::new(),
                 sid: String::new(),
                 reply_to: None,
+                headers: None,
                 payload: bytes::Bytes::new(),
             }.into_vec()
         })
@@ -1,10 +1,10 @@
 use bytes::Bytes;
 
 use futures::{
-    future::{self, Either},
+    future::{self, Either, Loop},
     prelude::*,
     stream,
-    sync::mpsc,
+    sync::{mpsc, oneshot},
     Future,
 };
 use parking_lot::RwLock;
@@ -13,13 +13,17 @@ use std::{
     net::{SocketAddr, ToSocketAddrs},
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio_executor;
+use tokio_timer::Timeout;
 use url::Url;
 
 use error::NatsError;
+use events::{NatsEvent, NatsEventEmitter, NatsEventRecord, NatsEventStats};
 use net::*;
-use protocol::{commands::*, Op};
+use protocol::{check_subject, commands::*, CommandError, Op};
+use rate_limit::{NatsRateLimit, ThrottledOps};
 
 /// Sink (write) part of a TCP stream
 type NatsSink = stream::SplitSink<NatsConnection>;
@@ -27,22 +31,104 @@ type NatsSink = stream::SplitSink<NatsConnection>;
 type NatsStream = stream::SplitStream<NatsConnection>;
 /// Useless pretty much, just for code semantics
 type NatsSubscriptionId = String;
+/// Stream of the OPs that aren't caught for subscriptions
+type NatsOtherStream = Box<dyn Stream<Item = Op, Error = NatsError> + Send + Sync>;
+
+/// Channel used to report the outcome of the final flush to whoever asked to close
+type CloseRequest = oneshot::Sender<Result<(), NatsError>>;
+
+/// Queue of the OPs to write. Once asked to close, it stops accepting OPs and ends after yielding everything
+/// that was accepted before, which makes `send_all` flush and close the sink right after
+#[derive(Debug)]
+struct DrainingQueue {
+    rx: mpsc::UnboundedReceiver<Op>,
+    shutdown: Option<oneshot::Receiver<CloseRequest>>,
+    closer: Arc<RwLock<Option<CloseRequest>>>,
+}
+
+impl Stream for DrainingQueue {
+    type Error = NatsError;
+    type Item = Op;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let shutdown = match self.shutdown.as_mut().map(|shutdown| shutdown.poll()) {
+            Some(Ok(Async::Ready(closer))) => Some(Some(closer)),
+            // Every sender is gone, the queue ends by itself
+            Some(Err(_)) => Some(None),
+            _ => None,
+        };
+
+        if let Some(maybe_closer) = shutdown {
+            self.shutdown = None;
+            if let Some(closer) = maybe_closer {
+                debug!(target: "nitox", "Closing, draining the remaining OPs");
+                *self.closer.write() = Some(closer);
+                self.rx.close();
+            }
+        }
+
+        self.rx.poll().map_err(|_| NatsError::InnerBrokenChain)
+    }
+}
 
 /// Keep-alive for the sink, also supposed to take care of handling verbose messaging, but can't for now
 #[derive(Clone, Debug)]
 struct NatsClientSender {
     tx: mpsc::UnboundedSender<Op>,
     verbose: bool,
+    shutdown: Arc<RwLock<Option<oneshot::Sender<CloseRequest>>>>,
 }
 
 impl NatsClientSender {
-    pub fn new(sink: NatsSink) -> Self {
+    /// Spawns the task writing the queued OPs to `sink`. With a publish rate limit, the queue holds the PUBs back
+    /// until the budget allows them, along with everything queued after them
+    pub fn new<S>(sink: S, rate_limit: Option<NatsRateLimit>) -> Self
+    where
+        S: Sink<SinkItem = Op, SinkError = NatsError> + Send + 'static,
+    {
         let (tx, rx) = mpsc::unbounded();
-        let rx = rx.map_err(|_| NatsError::InnerBrokenChain);
-        let work = sink.send_all(rx).map(|_| ()).map_err(|_| ());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let closer = Arc::new(RwLock::new(None));
+        let queue = DrainingQueue {
+            rx,
+            shutdown: Some(shutdown_rx),
+            closer: Arc::clone(&closer),
+        };
+
+        let written = match rate_limit {
+            Some(limit) => Either::A(sink.send_all(ThrottledOps::new(queue, limit, Instant::now())).map(|_| ())),
+            None => Either::B(sink.send_all(queue).map(|_| ())),
+        };
+
+        let work = written.then(move |res| {
+            if let Some(closer) = closer.write().take() {
+                let _ = closer.send(res);
+            }
+
+            Ok(())
+        });
         tokio_executor::spawn(work);
 
-        NatsClientSender { tx, verbose: false }
+        NatsClientSender {
+            tx,
+            verbose: false,
+            shutdown: Arc::new(RwLock::new(Some(shutdown_tx))),
+        }
+    }
+
+    /// Stops accepting OPs, and resolves once everything accepted before got written and the connection closed
+    pub fn close(&self) -> impl Future<Item = (), Error = NatsError> {
+        let (closer, closed) = oneshot::channel();
+        let requested = match self.shutdown.write().take() {
+            Some(shutdown) => shutdown.send(closer).is_ok(),
+            None => false,
+        };
+
+        if !requested {
+            return Either::A(future::err(NatsError::InnerBrokenChain));
+        }
+
+        Either::B(closed.map_err(|_| NatsError::InnerBrokenChain).and_then(|res| res))
     }
 
     #[allow(dead_code)]
@@ -53,13 +139,50 @@ impl NatsClientSender {
     /// Sends an OP to the server
     pub fn send(&self, op: Op) -> impl Future<Item = (), Error = NatsError> {
         //let _verbose = self.verbose.clone();
-        self.tx
-            .unbounded_send(op)
-            .map_err(|_| NatsError::InnerBrokenChain)
-            .into_future()
+        self.try_send(op).into_future()
+    }
+
+    /// Queues an OP right away, telling whether it got accepted
+    pub fn try_send(&self, op: Op) -> Result<(), NatsError> {
+        self.tx.unbounded_send(op).map_err(|_| NatsError::InnerBrokenChain)
+    }
+
+    /// Queues several OPs at once. The sink drains everything that is queued before flushing, so a batch
+    /// gets written to the socket in as few flushes as possible. See `order_batch` for the ordering guarantees
+    pub fn send_batch(&self, ops: Vec<Op>) -> impl Future<Item = (), Error = NatsError> {
+        let mut res = Ok(());
+        for op in order_batch(ops) {
+            if self.tx.unbounded_send(op).is_err() {
+                res = Err(NatsError::InnerBrokenChain);
+                break;
+            }
+        }
+
+        res.into_future()
     }
 }
 
+/// Reorders a batch so that its SUB commands are written before its PUB commands, which lets a batch publish
+/// to a subject it subscribes to. SUBs are never moved across OPs that are neither SUB nor PUB (such as CONNECT
+/// or UNSUB), and the relative order of the SUBs and of the PUBs is kept
+fn order_batch(ops: Vec<Op>) -> Vec<Op> {
+    let mut ordered = Vec::with_capacity(ops.len());
+    let mut pubs = Vec::new();
+    for op in ops {
+        match op {
+            Op::PUB(_) => pubs.push(op),
+            Op::SUB(_) => ordered.push(op),
+            op => {
+                ordered.extend(pubs.drain(..));
+                ordered.push(op);
+            }
+        }
+    }
+
+    ordered.extend(pubs);
+    ordered
+}
+
 #[derive(Debug)]
 struct SubscriptionSink {
     tx: mpsc::UnboundedSender<Message>,
@@ -70,25 +193,48 @@ struct SubscriptionSink {
 /// Internal multiplexer for incoming streams and subscriptions. Quite a piece of code, with almost no overhead yay
 #[derive(Debug)]
 struct NatsClientMultiplexer {
-    other_tx: Arc<mpsc::UnboundedSender<Op>>,
     subs_tx: Arc<RwLock<HashMap<NatsSubscriptionId, SubscriptionSink>>>,
+    taps_tx: Arc<RwLock<Vec<mpsc::UnboundedSender<Op>>>>,
 }
 
 impl NatsClientMultiplexer {
-    pub fn new(stream: NatsStream) -> (Self, mpsc::UnboundedReceiver<Op>) {
+    pub fn new<S>(stream: S, control_tx: NatsClientSender) -> (Self, mpsc::UnboundedReceiver<Op>)
+    where
+        S: Stream<Item = Op, Error = NatsError> + Send + 'static,
+    {
         let subs_tx: Arc<RwLock<HashMap<NatsSubscriptionId, SubscriptionSink>>> =
             Arc::new(RwLock::new(HashMap::default()));
 
+        // Only owned by the dispatching task, so that the rest of the OPs stop once the connection ends
         let (other_tx, other_rx) = mpsc::unbounded();
-        let other_tx = Arc::new(other_tx);
+
+        let taps_tx: Arc<RwLock<Vec<mpsc::UnboundedSender<Op>>>> = Arc::new(RwLock::new(Vec::new()));
 
         let stx_inner = Arc::clone(&subs_tx);
-        let otx_inner = Arc::clone(&other_tx);
+        let ttx_inner = Arc::clone(&taps_tx);
 
         // Here we filter the incoming TCP stream Messages by subscription ID and sending it to the appropriate Sender
         let work_tx = stream
             .for_each(move |op| {
+                // Read-only views get a copy of everything before it's dispatched
+                if !(*ttx_inner.read()).is_empty() {
+                    (*ttx_inner.write()).retain(|tx| tx.unbounded_send(op.clone()).is_ok());
+                }
+
                 match op {
+                    // JetStream control traffic is handled here and never reaches the subscriptions
+                    Op::MSG(ref msg) if msg.is_jetstream_control() => {
+                        debug!(target: "nitox", "Found JetStream control message {:?}", msg);
+                        // Flow control requests, and the heartbeats of stalled consumers, expect an empty reply
+                        // to resume delivery
+                        if let Some(reply_to) = msg.flow_control_reply() {
+                            let _ = control_tx.send(Op::PUB(PubCommand {
+                                subject: reply_to.into(),
+                                reply_to: None,
+                                payload: Bytes::new(),
+                            }));
+                        }
+                    }
                     Op::MSG(msg) => {
                         debug!(target: "nitox", "Found MSG from global Stream {:?}", msg);
                         if let Some(s) = (*stx_inner.read()).get(&msg.sid) {
@@ -99,7 +245,7 @@ impl NatsClientMultiplexer {
                     // Forward the rest of the messages to the owning client
                     op => {
                         debug!(target: "nitox", "Sending OP to the rest of the queue: {:?}", op);
-                        let _ = otx_inner.unbounded_send(op);
+                        let _ = other_tx.unbounded_send(op);
                     }
                 }
 
@@ -109,7 +255,10 @@ impl NatsClientMultiplexer {
 
         tokio_executor::spawn(work_tx);
 
-        (NatsClientMultiplexer { subs_tx, other_tx }, other_rx)
+        (
+            NatsClientMultiplexer { subs_tx, taps_tx },
+            other_rx,
+        )
     }
 
     pub fn for_sid(&self, sid: NatsSubscriptionId) -> impl Stream<Item = Message, Error = NatsError> + Send + Sync {
@@ -129,16 +278,167 @@ impl NatsClientMultiplexer {
     pub fn remove_sid(&self, sid: &str) {
         (*self.subs_tx.write()).remove(sid);
     }
+
+    /// Drops the sink of every subscription, which ends their streams
+    pub fn remove_all(&self) {
+        (*self.subs_tx.write()).clear();
+    }
+
+    /// Moves the subscription sink registered under `old_sid` to `new_sid`. Messages still routed to `old_sid`
+    /// are dropped from now on. The auto-unsubscription limit carries over, and the number of messages left
+    /// before reaching it is returned
+    pub fn rename_sid(&self, old_sid: &str, new_sid: NatsSubscriptionId) -> Option<u32> {
+        let mut stx = self.subs_tx.write();
+        let s = stx.remove(old_sid)?;
+        let remaining = s.max_count.map(|max_count| max_count.saturating_sub(s.count));
+        stx.insert(new_sid, s);
+        remaining
+    }
+
+    /// Registers a receiver getting a copy of every incoming OP, without any access to the connection itself
+    pub fn tap(&self) -> mpsc::UnboundedReceiver<Op> {
+        let (tx, rx) = mpsc::unbounded();
+        (*self.taps_tx.write()).push(tx);
+        rx
+    }
+}
+
+/// Handle over a subscription, allowing to change its subject without tearing down its message `Stream`
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    /// Current subscription id; It changes along with the subject
+    sid: Arc<RwLock<NatsSubscriptionId>>,
+    /// Queue group the subscription belongs to, kept when changing subject
+    queue_group: Option<String>,
+    tx: NatsClientSender,
+    rx: Arc<NatsClientMultiplexer>,
+    handshake: Arc<NatsHandshake>,
+}
+
+impl Subscription {
+    /// Returns the current subscription id
+    pub fn sid(&self) -> String {
+        self.sid.read().clone()
+    }
+
+    /// Subscribes to `subject` under a new sid and unsubscribes the old one, while the same `Stream` keeps
+    /// yielding messages. Messages for the old subject still in flight are discarded as their sid isn't routed
+    /// anymore, so the stream only yields messages of the new subject once this is called. A limit set through
+    /// `UNSUB` with `max_msgs` is kept, counting the messages already received
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn change_subject(&self, subject: String) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        if let Err(e) = check_subject(&subject) {
+            return Either::A(future::err(e));
+        }
+
+        let sub_cmd = match SubCommand::builder()
+            .subject(subject)
+            .queue_group(self.queue_group.clone())
+            .build()
+        {
+            Ok(cmd) => cmd,
+            Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+        };
+
+        let new_sid = sub_cmd.sid.clone();
+        let mut sid = self.sid.write();
+        let old_sid = ::std::mem::replace(&mut *sid, new_sid.clone());
+        let remaining = self.rx.rename_sid(&old_sid, new_sid.clone());
+        let old_cmd = {
+            let mut subscriptions = self.handshake.subscriptions.write();
+            subscriptions.insert(
+                new_sid.clone(),
+                ActiveSubscription {
+                    cmd: sub_cmd.clone(),
+                    max_msgs: remaining,
+                },
+            );
+            subscriptions.remove(&old_sid)
+        };
+
+        if let Err(e) = self.tx.try_send(Op::SUB(sub_cmd)) {
+            // Puts everything back so that the stream keeps yielding the messages of the old subject
+            self.rx.rename_sid(&new_sid, old_sid.clone());
+            let mut subscriptions = self.handshake.subscriptions.write();
+            subscriptions.remove(&new_sid);
+            if let Some(old_cmd) = old_cmd {
+                subscriptions.insert(old_sid.clone(), old_cmd);
+            }
+            *sid = old_sid;
+            return Either::A(future::err(e));
+        }
+
+        let mut ops = Vec::with_capacity(2);
+        if let Some(remaining) = remaining {
+            ops.push(Op::UNSUB(UnsubCommand {
+                sid: new_sid,
+                max_msgs: Some(remaining),
+            }));
+        }
+        ops.push(Op::UNSUB(UnsubCommand {
+            sid: old_sid,
+            max_msgs: None,
+        }));
+
+        Either::B(self.tx.send_batch(ops))
+    }
+}
+
+/// Removes the subscription made by `NatsClient::wait_for` once it's dropped, so that the subscription doesn't
+/// outlive the wait even if its future gets dropped before resolving
+#[derive(Debug)]
+struct WaitForGuard {
+    sid: NatsSubscriptionId,
+    tx: NatsClientSender,
+    rx: Arc<NatsClientMultiplexer>,
+    handshake: Arc<NatsHandshake>,
+}
+
+impl Drop for WaitForGuard {
+    fn drop(&mut self) {
+        debug!(target: "nitox", "Removing the subscription {} of a finished wait", self.sid);
+        self.rx.remove_sid(&self.sid);
+        self.handshake.subscriptions.write().remove(&self.sid);
+        let _ = self.tx.try_send(Op::UNSUB(UnsubCommand {
+            sid: self.sid.clone(),
+            max_msgs: None,
+        }));
+    }
 }
 
 /// Options that are to be given to the client for initialization
 #[derive(Debug, Default, Clone, Builder)]
-#[builder(setter(into))]
+#[builder(setter(into), build_fn(validate = "Self::validate"))]
 pub struct NatsClientOptions {
     /// CONNECT command that will be sent upon calling the `connect()` method
     pub connect_command: ConnectCommand,
     /// Cluster URI in the IP:PORT format
     pub cluster_uri: String,
+    /// If set, connection events are only emitted once they've been stable for this duration; flaps occuring
+    /// within the window are coalesced into a single event but still counted in `NatsClient::event_stats()`
+    #[builder(default)]
+    pub reconnect_debounce: Option<Duration>,
+    /// Number of connection events kept in the log returned by `NatsClient::recent_events()`
+    #[builder(default = "32")]
+    pub recent_events_size: usize,
+    /// When reconnecting, sends CONNECT right after the socket is up instead of waiting for the server INFO, trimming
+    /// a round trip. Ignored for TLS connections, where INFO is mandatory
+    #[builder(default)]
+    pub pipeline_connect: bool,
+    /// Signs the server nonce for NKEY/creds authentication. The nonce changes on every connection, so it's signed
+    /// again after each reconnection
+    #[builder(default)]
+    pub nonce_signer: Option<NatsNonceSigner>,
+    /// Backs off reconnections refused by a server that reached its maximum number of connections. Without it, such
+    /// a refusal fails the reconnection and the client stops reconnecting. A refusal of the first connection is
+    /// never retried, `connect` fails with `MaxConnectionsExceeded` instead
+    #[builder(default)]
+    pub max_connections_backoff: Option<NatsBackoff>,
+    /// Limits the rate of outbound publishes. Publishes are still queued right away, and written in order as fast
+    /// as the budget allows
+    #[builder(default)]
+    pub publish_rate_limit: Option<NatsRateLimit>,
 }
 
 impl NatsClientOptions {
@@ -147,8 +447,25 @@ impl NatsClientOptions {
     }
 }
 
+impl NatsClientOptionsBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(Some(ref limit)) = self.publish_rate_limit {
+            // A rate of 0 would never refill its budget
+            if limit.msgs_per_sec == Some(0) || limit.bytes_per_sec == Some(0) {
+                return Err("Publish rate limits must be greater than 0, leave them unset for no limit".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// The NATS Client. What you'll be using mostly. All the async handling is made internally except for
 /// the system messages that are forwarded on the `Stream` that the client implements
+///
+/// The client doesn't rely on any global state: its background tasks are spawned on the executor polling
+/// `from_options`, so each runtime (or each worker of a multi-threaded server) can own an independent client.
+/// A client stays bound to the runtime that created it, see `LazyNatsClient` to connect from within each worker
 pub struct NatsClient {
     /// Backup of options
     opts: NatsClientOptions,
@@ -160,6 +477,10 @@ pub struct NatsClient {
     tx: NatsClientSender,
     /// Subscription multiplexer
     rx: Arc<NatsClientMultiplexer>,
+    /// Connection lifecycle events emitter
+    events: Arc<NatsEventEmitter>,
+    /// Handshake shared with the connection, replayed on reconnection
+    handshake: Arc<NatsHandshake>,
 }
 
 impl ::std::fmt::Debug for NatsClient {
@@ -168,6 +489,7 @@ impl ::std::fmt::Debug for NatsClient {
             .field("opts", &self.opts)
             .field("tx", &self.tx)
             .field("rx", &self.rx)
+            .field("events", &self.events)
             .field("other_rx", &"Box<Stream>...")
             .finish()
     }
@@ -188,6 +510,16 @@ impl NatsClient {
     /// Returns `impl Future<Item = Self, Error = NatsError>`
     pub fn from_options(opts: NatsClientOptions) -> impl Future<Item = Self, Error = NatsError> + Send + Sync {
         let tls_required = opts.connect_command.tls_required;
+        let events = Arc::new(NatsEventEmitter::new(opts.reconnect_debounce, opts.recent_events_size));
+        let conn_events = Arc::clone(&events);
+        let refusal_events = Arc::clone(&events);
+        let handshake = Arc::new(NatsHandshake {
+            pipeline_connect: opts.pipeline_connect,
+            nonce_signer: opts.nonce_signer.clone(),
+            max_connections_breaker: opts.max_connections_backoff.map(MaxConnectionsBreaker::new),
+            ..Default::default()
+        });
+        let conn_handshake = Arc::clone(&handshake);
 
         let cluster_uri = opts.cluster_uri.clone();
         let cluster_sa = if let Ok(sockaddr) = SocketAddr::from_str(&cluster_uri) {
@@ -205,27 +537,52 @@ impl NatsClient {
                 if tls_required {
                     match Url::parse(&cluster_uri) {
                         Ok(url) => match url.host_str() {
-                            Some(host) => future::ok(Either::B(connect_tls(host.to_string(), cluster_sa))),
+                            Some(host) => future::ok(Either::B(connect_tls(
+                                host.to_string(),
+                                cluster_sa,
+                                conn_events,
+                                conn_handshake,
+                            ))),
                             None => future::err(NatsError::TlsHostMissingError),
                         },
                         Err(e) => future::err(e.into()),
                     }
                 } else {
-                    future::ok(Either::A(connect(cluster_sa)))
+                    future::ok(Either::A(connect(cluster_sa, conn_events, conn_handshake)))
                 }
             }).and_then(|either| either)
-            .and_then(move |connection| {
+            // The server always starts by sending its INFO, which we need before sending CONNECT
+            .and_then(|connection| connection.into_future().map_err(|(e, _)| e))
+            .and_then(move |(maybe_info, connection)| {
+                match maybe_info {
+                    Some(Op::INFO(server_info)) => {
+                        *handshake.server_info.write() = Some(server_info);
+                    }
+                    Some(Op::ERR(err)) => {
+                        if err.is_max_connections_exceeded() {
+                            refusal_events.emit(NatsEvent::MaxConnectionsExceeded);
+                        }
+                        return Err(err.into());
+                    }
+                    Some(_) => return Err(CommandError::CommandMalformed.into()),
+                    None => return Err(NatsError::ServerDisconnected(None)),
+                }
+
+                Ok((connection, handshake))
+            }).and_then(move |(connection, handshake)| {
                 let (sink, stream): (NatsSink, NatsStream) = connection.split();
-                let (rx, other_rx) = NatsClientMultiplexer::new(stream);
-                let tx = NatsClientSender::new(sink);
+                let tx = NatsClientSender::new(sink, opts.publish_rate_limit);
+                let (rx, other_rx) = NatsClientMultiplexer::new(stream, tx.clone());
 
                 let (tmp_other_tx, tmp_other_rx) = mpsc::unbounded();
                 let tx_inner = tx.clone();
                 let client = NatsClient {
                     tx,
-                    server_info: Arc::new(RwLock::new(None)),
+                    server_info: Arc::clone(&handshake.server_info),
                     other_rx: Box::new(tmp_other_rx.map_err(|_| NatsError::InnerBrokenChain)),
                     rx: Arc::new(rx),
+                    events,
+                    handshake,
                     opts,
                 };
 
@@ -256,13 +613,137 @@ impl NatsClient {
             })
     }
 
-    /// Sends the CONNECT command to the server to setup connection
+    /// Sends the CONNECT command to the server to setup connection, followed by a PING. Resolves once the server
+    /// answered it, or fails with `MaxConnectionsExceeded` if the server is full and refused the connection,
+    /// `ServerError` if it answered with any other -ERR, and `ServerDisconnected` if the connection dropped
+    /// in the meantime
     ///
     /// Returns `impl Future<Item = Self, Error = NatsError>`
     pub fn connect(self) -> impl Future<Item = Self, Error = NatsError> + Send + Sync {
-        self.tx
-            .send(Op::CONNECT(self.opts.connect_command.clone()))
-            .and_then(move |_| future::ok(self))
+        *self.handshake.connect_command.write() = Some(self.opts.connect_command.clone());
+        let signed_cmd = self
+            .handshake
+            .sign_connect(self.opts.connect_command.clone(), self.server_info.read().as_ref());
+
+        // Listening before sending CONNECT so that a drop right after can't go unnoticed
+        let interrupted = self
+            .events
+            .listen()
+            .filter_map(|event| match event {
+                NatsEvent::MaxConnectionsExceeded => Some(NatsError::MaxConnectionsExceeded),
+                NatsEvent::Disconnected | NatsEvent::ReconnectFailed => Some(NatsError::ServerDisconnected(None)),
+                _ => None,
+            }).into_future()
+            .map_err(|_| NatsError::InnerBrokenChain)
+            .and_then(|(maybe_err, _)| -> Result<NatsOtherStream, NatsError> {
+                Err(maybe_err.unwrap_or(NatsError::InnerBrokenChain))
+            });
+
+        let tx = self.tx.clone();
+        future::result(signed_cmd)
+            .and_then(move |cmd| tx.send_batch(vec![Op::CONNECT(cmd), Op::PING]))
+            .and_then(move |_| {
+                let mut client = self;
+                let other_rx = ::std::mem::replace(&mut client.other_rx, Box::new(stream::empty()));
+                Self::wait_for_pong(other_rx)
+                    .select(interrupted)
+                    .map_err(|(e, _)| e)
+                    .map(move |(other_rx, _)| {
+                        client.other_rx = other_rx;
+                        client
+                    })
+            })
+    }
+
+    /// Reads `other_rx` until the PONG answering the PING sent along with CONNECT. Everything else read in the
+    /// meantime is put back in front of the returned stream
+    fn wait_for_pong(
+        other_rx: NatsOtherStream,
+    ) -> impl Future<Item = NatsOtherStream, Error = NatsError> + Send + Sync {
+        future::loop_fn((other_rx, Vec::new()), |(other_rx, mut skipped)| {
+            other_rx
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(maybe_op, other_rx)| match maybe_op {
+                    Some(Op::PONG) => Ok(Loop::Break((other_rx, skipped))),
+                    Some(Op::ERR(err)) => Err(err.into()),
+                    Some(op) => {
+                        skipped.push(op);
+                        Ok(Loop::Continue((other_rx, skipped)))
+                    }
+                    None => Err(NatsError::ServerDisconnected(None)),
+                })
+        }).map(|(other_rx, skipped)| -> NatsOtherStream {
+            Box::new(stream::iter_ok::<_, NatsError>(skipped).chain(other_rx))
+        })
+    }
+
+    /// Closes the connection. Every OP accepted before, such as a publish whose future already resolved, is written
+    /// to the socket before it gets shut down, while the ones sent afterwards fail with `InnerBrokenChain`. Every
+    /// subscription stream ends once the connection is closed
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn close(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let rx = Arc::clone(&self.rx);
+        self.tx.close().then(move |res| {
+            rx.remove_all();
+            res
+        })
+    }
+
+    /// Unsubscribes from every active subscription, ending their streams right away, then closes the connection like
+    /// `close` does
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn drain(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let unsubs = self
+            .handshake
+            .subscriptions
+            .write()
+            .drain()
+            .map(|(sid, _)| {
+                self.rx.remove_sid(&sid);
+                Op::UNSUB(UnsubCommand { sid, max_msgs: None })
+            }).collect();
+
+        let tx = self.tx.clone();
+        let rx = Arc::clone(&self.rx);
+        self.tx.send_batch(unsubs).and_then(move |_| tx.close()).then(move |res| {
+            rx.remove_all();
+            res
+        })
+    }
+
+    /// Listens to the connection lifecycle events (disconnections and reconnections)
+    ///
+    /// Returns `impl Stream<Item = NatsEvent, Error = NatsError>`
+    pub fn events(&self) -> impl Stream<Item = NatsEvent, Error = NatsError> + Send + Sync {
+        self.events.listen().map_err(|_| NatsError::InnerBrokenChain)
+    }
+
+    /// Counters of all the connection events that occured, including the ones coalesced by the debounce
+    pub fn event_stats(&self) -> NatsEventStats {
+        self.events.stats()
+    }
+
+    /// Returns a read-only view of every OP coming from the server. This view is fed by the multiplexer, so it
+    /// never locks the underlying connection for writing nor triggers reconnections, which makes it suited for
+    /// monitoring tools tapping a connection shared with a publisher
+    ///
+    /// Returns `impl Stream<Item = Op, Error = NatsError>`
+    pub fn subscribe_readonly(&self) -> impl Stream<Item = Op, Error = NatsError> + Send + Sync {
+        self.rx.tap().map_err(|_| NatsError::InnerBrokenChain)
+    }
+
+    /// Details of the negotiated TLS session, or `None` for plaintext connections
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.handshake.tls_info.read().clone()
+    }
+
+    /// Snapshot of the most recent connection events, from the oldest to the latest. Useful for observers
+    /// attaching late to the event stream
+    pub fn recent_events(&self) -> Vec<NatsEventRecord> {
+        self.events.recent()
     }
 
     /// Send a raw command to the server
@@ -276,17 +757,32 @@ impl NatsClient {
         self.tx.send(op).and_then(move |_| future::ok(self))
     }
 
-    /// Send a PUB command to the server
+    /// Send a PUB command to the server. The command is queued right away, before the returned future is even
+    /// polled. With a publish rate limit, it's written once the budget allows it, after every command queued before
     ///
     /// Returns `impl Future<Item = (), Error = NatsError>`
     pub fn publish(&self, cmd: PubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        if let Err(e) = self.check_publish(&cmd) {
+            return Either::A(future::err(e));
+        }
+
+        Either::B(self.tx.send(Op::PUB(cmd)))
+    }
+
+    /// Checks that a PUB command can be sent as-is to the server
+    fn check_publish(&self, cmd: &PubCommand) -> Result<(), NatsError> {
+        check_subject(&cmd.subject)?;
+        if let Some(ref reply_to) = cmd.reply_to {
+            check_subject(reply_to)?;
+        }
+
         if let Some(ref server_info) = *self.server_info.read() {
             if cmd.payload.len() > server_info.max_payload as usize {
-                return Either::A(future::err(NatsError::MaxPayloadOverflow(server_info.max_payload)));
+                return Err(NatsError::MaxPayloadOverflow(server_info.max_payload));
             }
         }
 
-        Either::B(self.tx.send(Op::PUB(cmd)))
+        Ok(())
     }
 
     /// Send a UNSUB command to the server and de-register stream in the multiplexer
@@ -296,7 +792,13 @@ impl NatsClient {
         if let Some(max) = cmd.max_msgs {
             if let Some(mut s) = (*self.rx.subs_tx.write()).get_mut(&cmd.sid) {
                 s.max_count = Some(max);
+                // Kept so that a reconnection only asks for the messages that are still expected
+                if let Some(active) = self.handshake.subscriptions.write().get_mut(&cmd.sid) {
+                    active.max_msgs = Some(max.saturating_sub(s.count));
+                }
             }
+        } else {
+            self.handshake.subscriptions.write().remove(&cmd.sid);
         }
 
         self.tx.send(Op::UNSUB(cmd))
@@ -310,40 +812,227 @@ impl NatsClient {
         cmd: SubCommand,
     ) -> impl Future<Item = impl Stream<Item = Message, Error = NatsError> + Send + Sync, Error = NatsError> + Send + Sync
     {
-        let inner_rx = self.rx.clone();
-        let sid = cmd.sid.clone();
-        self.tx.send(Op::SUB(cmd)).and_then(move |_| {
-            let stream = inner_rx.for_sid(sid.clone()).and_then(move |msg| {
-                {
-                    let mut stx = inner_rx.subs_tx.write();
-                    let mut delete = None;
-                    debug!(target: "nitox", "Retrieving sink for sid {:?}", sid);
-                    if let Some(s) = stx.get_mut(&sid) {
-                        debug!(target: "nitox", "Checking if count exists");
-                        if let Some(max_count) = s.max_count {
-                            s.count += 1;
-                            debug!(target: "nitox", "Max: {} / current: {}", max_count, s.count);
-                            if s.count >= max_count {
-                                debug!(target: "nitox", "Starting deletion");
-                                delete = Some(max_count);
-                            }
-                        }
-                    }
+        self.subscribe_with_handle(cmd).map(|(_, stream)| stream)
+    }
+
+    /// Subscribes to `subject` and applies `transform` to every message before yielding it, for instance to
+    /// decode or decrypt payloads uniformly. Transformation failures are yielded as items so they don't end the
+    /// stream, which only fails on subscription errors
+    ///
+    /// Returns `impl Future<Item = impl Stream<Item = Result<T, NatsError>, Error = NatsError>>`
+    pub fn subscribe_map<T, F>(
+        &self,
+        subject: String,
+        transform: F,
+    ) -> impl Future<
+        Item = impl Stream<Item = Result<T, NatsError>, Error = NatsError> + Send + Sync,
+        Error = NatsError,
+    > + Send
+           + Sync
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Message) -> Result<T, NatsError> + Send + Sync + 'static,
+    {
+        if let Err(e) = check_subject(&subject) {
+            return Either::A(future::err(e));
+        }
+
+        let cmd = match SubCommand::builder().subject(subject).build() {
+            Ok(cmd) => cmd,
+            Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+        };
+
+        Either::B(self.subscribe(cmd).map(move |stream| stream.map(transform)))
+    }
+
+    /// Same as `subscribe`, but also returns a `Subscription` handle allowing to change the subject of the
+    /// subscription at runtime while keeping the same `Stream`
+    ///
+    /// Returns `impl Future<Item = (Subscription, impl Stream<Item = Message, Error = NatsError>)>`
+    pub fn subscribe_with_handle(
+        &self,
+        cmd: SubCommand,
+    ) -> impl Future<
+        Item = (Subscription, impl Stream<Item = Message, Error = NatsError> + Send + Sync),
+        Error = NatsError,
+    > + Send
+           + Sync {
+        if let Err(e) = check_subject(&cmd.subject) {
+            return Either::A(future::err(e));
+        }
+
+        let sid = Arc::new(RwLock::new(cmd.sid.clone()));
+        let handle = Subscription {
+            sid: Arc::clone(&sid),
+            queue_group: cmd.queue_group.clone(),
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+            handshake: Arc::clone(&self.handshake),
+        };
+
+        let stream = self.subscription_stream(sid);
+        self.handshake
+            .subscriptions
+            .write()
+            .insert(cmd.sid.clone(), cmd.clone().into());
+
+        Either::B(self.tx.send(Op::SUB(cmd)).map(move |_| (handle, stream)))
+    }
+
+    /// Subscribes to all the given subjects at once. The SUB commands are queued as a single batch so they get
+    /// written in as few flushes as possible, which matters for clients with many eager subscriptions
+    ///
+    /// Returns `impl Future<Item = Vec<impl Stream<Item = Message, Error = NatsError>>>`, in the same order as `cmds`
+    pub fn subscribe_all(
+        &self,
+        cmds: Vec<SubCommand>,
+    ) -> impl Future<
+        Item = Vec<impl Stream<Item = Message, Error = NatsError> + Send + Sync>,
+        Error = NatsError,
+    > + Send
+           + Sync {
+        self.send_batch(cmds.into_iter().map(Op::SUB).collect())
+    }
+
+    /// Sends SUB and PUB commands as a single batch, written in as few flushes as possible. Within the batch, SUB
+    /// commands are always written before PUB commands, whatever their order in `ops`, so that the server knows
+    /// about the subscriptions before routing the publishes.
+    ///
+    /// Note that receiving your own messages also requires the `echo` option of the CONNECT command not to be
+    /// disabled, which is the server's default
+    ///
+    /// Returns `impl Future<Item = Vec<impl Stream<Item = Message, Error = NatsError>>>`, one per SUB command in
+    /// the same order as in `ops`
+    pub fn send_batch(
+        &self,
+        ops: Vec<Op>,
+    ) -> impl Future<
+        Item = Vec<impl Stream<Item = Message, Error = NatsError> + Send + Sync>,
+        Error = NatsError,
+    > + Send
+           + Sync {
+        for op in &ops {
+            let checked = match *op {
+                Op::SUB(ref cmd) => check_subject(&cmd.subject),
+                Op::PUB(ref cmd) => self.check_publish(cmd),
+                _ => Err(NatsError::CommandBuildError("Only SUB and PUB commands can be batched".into())),
+            };
+
+            if let Err(e) = checked {
+                return Either::A(future::err(e));
+            }
+        }
 
-                    if let Some(count) = delete.take() {
-                        debug!(target: "nitox", "Deleted stream for sid {} at count {}", sid, count);
-                        stx.remove(&sid);
-                        return Err(NatsError::SubscriptionReachedMaxMsgs(count));
+        let mut streams = Vec::new();
+        for op in &ops {
+            if let Op::SUB(ref cmd) = *op {
+                streams.push(self.subscription_stream(Arc::new(RwLock::new(cmd.sid.clone()))));
+                self.handshake
+                    .subscriptions
+                    .write()
+                    .insert(cmd.sid.clone(), cmd.clone().into());
+            }
+        }
+
+        Either::B(self.tx.send_batch(ops).map(move |_| streams))
+    }
+
+    /// Registers a subscription stream in the multiplexer, taking care of the auto-unsubscription after the
+    /// maximum number of messages has been reached
+    fn subscription_stream(
+        &self,
+        sid: Arc<RwLock<NatsSubscriptionId>>,
+    ) -> impl Stream<Item = Message, Error = NatsError> + Send + Sync {
+        let inner_rx = Arc::clone(&self.rx);
+        let handshake = Arc::clone(&self.handshake);
+        let current_sid = sid.read().clone();
+
+        self.rx.for_sid(current_sid).and_then(move |msg| {
+            {
+                let sid = sid.read().clone();
+                let mut stx = inner_rx.subs_tx.write();
+                let mut delete = None;
+                debug!(target: "nitox", "Retrieving sink for sid {:?}", sid);
+                if let Some(s) = stx.get_mut(&sid) {
+                    debug!(target: "nitox", "Checking if count exists");
+                    if let Some(max_count) = s.max_count {
+                        s.count += 1;
+                        debug!(target: "nitox", "Max: {} / current: {}", max_count, s.count);
+                        if let Some(active) = handshake.subscriptions.write().get_mut(&sid) {
+                            active.max_msgs = Some(max_count.saturating_sub(s.count));
+                        }
+                        if s.count >= max_count {
+                            debug!(target: "nitox", "Starting deletion");
+                            delete = Some(max_count);
+                        }
                     }
                 }
 
-                Ok(msg)
-            });
+                if let Some(count) = delete.take() {
+                    debug!(target: "nitox", "Deleted stream for sid {} at count {}", sid, count);
+                    stx.remove(&sid);
+                    handshake.subscriptions.write().remove(&sid);
+                    return Err(NatsError::SubscriptionReachedMaxMsgs(count));
+                }
+            }
 
-            future::ok(stream)
+            Ok(msg)
         })
     }
 
+    /// Subscribes to `subject` and resolves with the first message for which `predicate` returns true, skipping
+    /// the others. The subscription is removed once done, whether a message matched, the wait timed out or failed,
+    /// and also when the returned future is dropped before resolving
+    ///
+    /// Returns `impl Future<Item = Message, Error = NatsError>`
+    pub fn wait_for<F>(
+        &self,
+        subject: String,
+        predicate: F,
+        timeout: Duration,
+    ) -> impl Future<Item = Message, Error = NatsError> + Send + Sync
+    where
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        if let Err(e) = check_subject(&subject) {
+            return Either::A(future::err(e));
+        }
+
+        let cmd = match SubCommand::builder().subject(subject).build() {
+            Ok(cmd) => cmd,
+            Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+        };
+
+        // Dropped along with the future, whether it resolved or not
+        let guard = WaitForGuard {
+            sid: cmd.sid.clone(),
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+            handshake: Arc::clone(&self.handshake),
+        };
+
+        let matching = self.subscribe(cmd).and_then(move |stream| {
+            stream
+                .filter(move |msg| predicate(msg))
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(|(maybe_msg, _)| maybe_msg.ok_or(NatsError::InnerBrokenChain))
+        });
+
+        Either::B(Timeout::new(matching, timeout).then(move |res| {
+            drop(guard);
+            res.map_err(|e| {
+                if e.is_elapsed() {
+                    NatsError::Timeout(timeout)
+                } else if e.is_timer() {
+                    NatsError::GenericError(e.to_string())
+                } else {
+                    e.into_inner().unwrap_or(NatsError::InnerBrokenChain)
+                }
+            })
+        }))
+    }
+
     /// Performs a request to the server following the Request/Reply pattern. Returns a future containing the MSG that will be replied at some point by a third party
     ///
     /// Returns `impl Future<Item = Message, Error = NatsError>`
@@ -352,6 +1041,10 @@ impl NatsClient {
         subject: String,
         payload: Bytes,
     ) -> impl Future<Item = Message, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_subject(&subject) {
+            return Either::A(future::err(e));
+        }
+
         if let Some(ref server_info) = *self.server_info.read() {
             if payload.len() > server_info.max_payload as usize {
                 return Either::A(future::err(NatsError::MaxPayloadOverflow(server_info.max_payload)));
@@ -404,3 +1097,248 @@ impl NatsClient {
         )
     }
 }
+
+type SharedNatsClient = future::Shared<Box<dyn Future<Item = Arc<NatsClient>, Error = NatsError> + Send>>;
+
+/// Connection attempt of a `LazyNatsClient`, numbered so that a failed attempt only forgets itself
+#[derive(Default)]
+struct LazyConnection {
+    attempt: usize,
+    client: Option<SharedNatsClient>,
+}
+
+/// Client that only connects (and sends its CONNECT command) on first use, on the runtime of that first use.
+/// Meant to be created before forking or spawning workers, each worker owning its own `LazyNatsClient`: clones
+/// share the same underlying connection, while separate instances never do
+#[derive(Clone)]
+pub struct LazyNatsClient {
+    opts: NatsClientOptions,
+    connection: Arc<RwLock<LazyConnection>>,
+}
+
+impl ::std::fmt::Debug for LazyNatsClient {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("LazyNatsClient")
+            .field("opts", &self.opts)
+            .field("started", &self.connection.read().client.is_some())
+            .finish()
+    }
+}
+
+impl LazyNatsClient {
+    pub fn new(opts: NatsClientOptions) -> Self {
+        LazyNatsClient {
+            opts,
+            connection: Arc::new(RwLock::new(LazyConnection::default())),
+        }
+    }
+
+    /// Returns the connected client, connecting first if this is the first use. Concurrent first uses share the
+    /// same connection attempt, and a failed attempt is forgotten so that the next use tries again. Every use
+    /// sharing a failed attempt gets the error it failed with
+    ///
+    /// Returns `impl Future<Item = Arc<NatsClient>, Error = NatsError>`
+    pub fn client(&self) -> impl Future<Item = Arc<NatsClient>, Error = NatsError> + Send + Sync {
+        let (attempt, shared) = {
+            let mut connection = self.connection.write();
+            if connection.client.is_none() {
+                let connecting: Box<dyn Future<Item = Arc<NatsClient>, Error = NatsError> + Send> = Box::new(
+                    NatsClient::from_options(self.opts.clone())
+                        .and_then(|client| client.connect())
+                        .map(Arc::new),
+                );
+                connection.attempt = connection.attempt.wrapping_add(1);
+                connection.client = Some(connecting.shared());
+            }
+
+            (connection.attempt, connection.client.clone().unwrap())
+        };
+
+        let connection = Arc::clone(&self.connection);
+        shared.map(|client| Arc::clone(&*client)).map_err(move |e| {
+            let mut connection = connection.write();
+            // A newer attempt may have started since, it must not be forgotten
+            if connection.attempt == attempt {
+                connection.client = None;
+            }
+
+            e.rebuild()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NatsClientMultiplexer, NatsClientOptions, NatsClientSender};
+    use error::NatsError;
+    use futures::{future, prelude::*, sync::mpsc};
+    use parking_lot::RwLock;
+    use protocol::{commands::*, Op};
+    use rate_limit::NatsRateLimit;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+    use tokio::runtime::{current_thread, Runtime};
+
+    /// Sink recording how many OPs got written by each flush
+    struct CountingSink {
+        buffered: usize,
+        flushes: Arc<RwLock<Vec<usize>>>,
+    }
+
+    impl Sink for CountingSink {
+        type SinkError = NatsError;
+        type SinkItem = Op;
+
+        fn start_send(&mut self, _op: Op) -> StartSend<Op, NatsError> {
+            self.buffered += 1;
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), NatsError> {
+            if self.buffered > 0 {
+                self.flushes.write().push(self.buffered);
+                self.buffered = 0;
+            }
+
+            Ok(Async::Ready(()))
+        }
+
+        fn close(&mut self) -> Poll<(), NatsError> {
+            self.poll_complete()
+        }
+    }
+
+    #[test]
+    fn it_writes_a_batch_in_a_single_flush() {
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        let flushes = Arc::new(RwLock::new(Vec::new()));
+        let sink = CountingSink {
+            buffered: 0,
+            flushes: Arc::clone(&flushes),
+        };
+
+        let sender = runtime
+            .block_on(future::lazy(move || {
+                let sender = NatsClientSender::new(sink, None);
+                let subs = (0..500)
+                    .map(|i| Op::SUB(SubCommand::builder().subject(format!("eager.{}", i)).build().unwrap()))
+                    .collect();
+                sender.send_batch(subs).map(move |_| sender)
+            })).unwrap();
+
+        runtime.block_on(sender.close()).unwrap();
+        assert_eq!(*flushes.read(), vec![500]);
+    }
+
+    /// Stream write-locking its inner connection on every poll, the way `NatsConnection` does
+    struct LockingStream {
+        ops: mpsc::UnboundedReceiver<Op>,
+        inner: Arc<RwLock<()>>,
+        write_locks: Arc<AtomicUsize>,
+        done: Arc<AtomicBool>,
+    }
+
+    impl Stream for LockingStream {
+        type Error = NatsError;
+        type Item = Op;
+
+        fn poll(&mut self) -> Poll<Option<Op>, NatsError> {
+            let _inner = self.inner.write();
+            self.write_locks.fetch_add(1, Ordering::SeqCst);
+            let polled = self.ops.poll().map_err(|_| NatsError::InnerBrokenChain)?;
+            if let Async::Ready(None) = polled {
+                self.done.store(true, Ordering::SeqCst);
+            }
+
+            Ok(polled)
+        }
+    }
+
+    #[test]
+    fn it_taps_without_locking_the_connection() {
+        let mut runtime = Runtime::new().unwrap();
+        let (ops_tx, ops_rx) = mpsc::unbounded();
+        let inner = Arc::new(RwLock::new(()));
+        let write_locks = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let stream = LockingStream {
+            ops: ops_rx,
+            inner: Arc::clone(&inner),
+            write_locks: Arc::clone(&write_locks),
+            done: Arc::clone(&done),
+        };
+
+        let (control_tx, _control_rx) = mpsc::unbounded();
+        let control_tx = NatsClientSender {
+            tx: control_tx,
+            verbose: false,
+            shutdown: Arc::new(RwLock::new(None)),
+        };
+
+        let tap = runtime
+            .block_on(future::lazy(move || {
+                let (multiplexer, _other_rx) = NatsClientMultiplexer::new(stream, control_tx);
+                Ok::<_, ()>(multiplexer.tap())
+            })).unwrap();
+
+        for i in 0..3 {
+            let msg = Message::builder()
+                .subject("foo")
+                .sid("pouet")
+                .payload(format!("bar{}", i))
+                .build()
+                .unwrap();
+            ops_tx.unbounded_send(Op::MSG(msg)).unwrap();
+        }
+        drop(ops_tx);
+
+        while !done.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Readers of the connection keep it locked while the tap is consumed, which would deadlock on a write lock
+        let _reader = inner.read();
+        let write_locks_before = write_locks.load(Ordering::SeqCst);
+        let tapped: Vec<Op> = tap.take(3).collect().wait().unwrap();
+        assert_eq!(tapped.len(), 3);
+        assert_eq!(write_locks.load(Ordering::SeqCst), write_locks_before);
+
+        let _ = runtime.shutdown_now().wait();
+    }
+
+    #[test]
+    fn it_rejects_zero_publish_rates() {
+        let options = |limit: NatsRateLimit| {
+            NatsClientOptions::builder()
+                .connect_command(ConnectCommand::builder().build().unwrap())
+                .cluster_uri("127.0.0.1:4222")
+                .publish_rate_limit(limit)
+                .build()
+        };
+
+        assert!(
+            options(NatsRateLimit {
+                msgs_per_sec: Some(0),
+                bytes_per_sec: None,
+            }).is_err()
+        );
+        assert!(
+            options(NatsRateLimit {
+                msgs_per_sec: Some(10),
+                bytes_per_sec: Some(0),
+            }).is_err()
+        );
+        assert!(
+            options(NatsRateLimit {
+                msgs_per_sec: Some(10),
+                bytes_per_sec: None,
+            }).is_ok()
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use bytes::{BufMut, BytesMut};
 use error::NatsError;
-use protocol::{CommandError, Op};
+use protocol::{commands::Message, CommandError, Op};
 use tokio_codec::{Decoder, Encoder};
 
 /// `tokio-codec` implementation of the protocol parsing
@@ -14,6 +14,23 @@ impl OpCodec {
     pub fn new() -> Self {
         OpCodec::default()
     }
+
+    /// Decodes an OP just like `Decoder::decode`, also returning the number of bytes consumed from `buf`.
+    /// Useful when driving the codec manually over custom transports
+    pub fn decode_with_consumed(&mut self, buf: &mut BytesMut) -> Result<Option<(Op, usize)>, NatsError> {
+        let len_before = buf.len();
+        Ok(self.decode(buf)?.map(|op| (op, len_before - buf.len())))
+    }
+}
+
+/// Reads the total length (headers + payload) declared at the end of a HMSG command line
+fn hmsg_total_len(line: &[u8]) -> Result<usize, CommandError> {
+    let total_len = line
+        .rsplit(|b| *b == b' ' || *b == b'\t')
+        .next()
+        .ok_or_else(|| CommandError::CommandMalformed)?;
+
+    Ok(::std::str::from_utf8(total_len)?.parse()?)
 }
 
 impl Encoder for OpCodec {
@@ -62,6 +79,20 @@ impl Decoder for OpCodec {
                         debug!(target: "nitox", "command was incomplete");
                         return Ok(None);
                     }
+                } else if &buf[..command_end] == Message::HMSG_CMD_NAME {
+                    // Headers contain CRLFs, so we rely on the declared total length instead
+                    let total_len = hmsg_total_len(&buf[..end_buf_pos - 2])?;
+                    let hmsg_end = end_buf_pos
+                        .checked_add(total_len)
+                        .and_then(|end| end.checked_add(2))
+                        .ok_or_else(|| CommandError::CommandMalformed)?;
+                    debug!(target: "nitox", "detected HMSG, expecting {} more bytes", hmsg_end - end_buf_pos);
+                    if buf.len() < hmsg_end {
+                        debug!(target: "nitox", "command was incomplete");
+                        return Ok(None);
+                    }
+
+                    end_buf_pos = hmsg_end;
                 }
 
                 debug!(target: "nitox", "codec detected command body {:?}", &buf[..end_buf_pos]);
@@ -95,3 +126,60 @@ impl Decoder for OpCodec {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::OpCodec;
+    use bytes::BytesMut;
+    use error::NatsError;
+    use protocol::{CommandError, Op};
+    use tokio_codec::Decoder;
+
+    #[test]
+    fn it_reports_consumed_bytes() {
+        let frames: Vec<&[u8]> = vec![
+            b"PING\r\n",
+            b"PONG\r\n",
+            b"+OK\r\n",
+            b"MSG\tFOO\tpouet\t4\r\ntoto\r\n",
+            b"PUB\tFOO\t11\r\nHello NATS!\r\n",
+            b"SUB\tFOO\tpouet\r\n",
+            b"UNSUB\tpouet\r\n",
+            b"HMSG\tFOO\tpouet\t12\t16\r\nNATS/1.0\r\n\r\ntoto\r\n",
+        ];
+
+        for frame in frames {
+            let mut codec = OpCodec::new();
+            let mut buf = BytesMut::from(frame);
+            buf.extend_from_slice(b"PING\r\n");
+
+            let (_, consumed) = codec.decode_with_consumed(&mut buf).unwrap().unwrap();
+            assert_eq!(consumed, frame.len());
+            assert_eq!(&buf[..], b"PING\r\n");
+
+            let (op, consumed) = codec.decode_with_consumed(&mut buf).unwrap().unwrap();
+            assert_eq!(op, Op::PING);
+            assert_eq!(consumed, 6);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn it_consumes_nothing_on_incomplete_frames() {
+        let mut codec = OpCodec::new();
+        let mut buf = BytesMut::from(&b"MSG\tFOO\tpouet\t4\r\nto"[..]);
+        assert!(codec.decode_with_consumed(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 19);
+    }
+
+    #[test]
+    fn it_rejects_overflowing_hmsg_lengths() {
+        let mut codec = OpCodec::new();
+        let hmsg = format!("HMSG\tFOO\tpouet\t12\t{}\r\nNATS/1.0\r\n\r\n", ::std::usize::MAX);
+        let mut buf = BytesMut::from(hmsg.as_bytes());
+        match codec.decode(&mut buf) {
+            Err(NatsError::ProtocolError(CommandError::CommandMalformed)) => {}
+            other => panic!("Expected a ProtocolError, got {:?}", other),
+        }
+    }
+}
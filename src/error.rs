@@ -65,6 +65,52 @@ pub enum NatsError {
     /// Error thrown when a subscription is fused after reaching the maximum messages
     #[fail(display = "SubscriptionReachedMaxMsgs after {} messages", _0)]
     SubscriptionReachedMaxMsgs(u32),
+    /// The subject (or reply inbox) is empty or contains spaces or control characters such as a newline, which
+    /// would corrupt the command framing
+    #[fail(display = "InvalidSubject: {:?}", _0)]
+    InvalidSubject(String),
+    /// The awaited message didn't come in time
+    #[fail(display = "Timeout: nothing happened after {:?}", _0)]
+    Timeout(::std::time::Duration),
+    /// The server refused the connection because it reached its maximum number of connections
+    #[fail(display = "MaxConnectionsExceeded: the server is full")]
+    MaxConnectionsExceeded,
+    /// The server answered with an -ERR, such as an authorization violation
+    #[fail(display = "ServerError: {}", _0)]
+    ServerError(String),
+}
+
+impl NatsError {
+    /// Rebuilds the same error from a shared reference to it, keeping its variant. Errors that can't be copied
+    /// (TLS, JSON) are kept as their message
+    pub(crate) fn rebuild(&self) -> Self {
+        match *self {
+            NatsError::CommandBuildError(ref s) => NatsError::CommandBuildError(s.clone()),
+            NatsError::IOError(ref e) => NatsError::IOError(rebuild_io_error(e)),
+            NatsError::ServerDisconnected(ref e) => NatsError::ServerDisconnected(e.as_ref().map(rebuild_io_error)),
+            NatsError::ProtocolError(ref e) => NatsError::ProtocolError(e.rebuild()),
+            NatsError::UTF8Error(ref e) => NatsError::UTF8Error(e.clone()),
+            NatsError::TlsError(ref e) => NatsError::GenericError(e.to_string()),
+            NatsError::TlsHostMissingError => NatsError::TlsHostMissingError,
+            NatsError::UrlParseError(e) => NatsError::UrlParseError(e),
+            NatsError::AddrParseError(ref e) => NatsError::AddrParseError(e.clone()),
+            NatsError::UriDNSResolveError(ref e) => NatsError::UriDNSResolveError(e.as_ref().map(rebuild_io_error)),
+            NatsError::CannotReconnectToServer => NatsError::CannotReconnectToServer,
+            NatsError::InnerBrokenChain => NatsError::InnerBrokenChain,
+            NatsError::MaxPayloadOverflow(max) => NatsError::MaxPayloadOverflow(max),
+            NatsError::GenericError(ref s) => NatsError::GenericError(s.clone()),
+            NatsError::SubscriptionReachedMaxMsgs(count) => NatsError::SubscriptionReachedMaxMsgs(count),
+            NatsError::InvalidSubject(ref s) => NatsError::InvalidSubject(s.clone()),
+            NatsError::Timeout(timeout) => NatsError::Timeout(timeout),
+            NatsError::MaxConnectionsExceeded => NatsError::MaxConnectionsExceeded,
+            NatsError::ServerError(ref s) => NatsError::ServerError(s.clone()),
+        }
+    }
+}
+
+/// `io::Error` isn't `Clone`, but its kind and message are enough to tell what happened
+fn rebuild_io_error(e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
 }
 
 impl From<io::Error> for NatsError {
@@ -0,0 +1,357 @@
+use futures::{prelude::*, sync::mpsc};
+use parking_lot::RwLock;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_executor;
+use tokio_timer::Delay;
+
+/// Lifecycle events of the underlying connection, forwarded on the client's event stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatsEvent {
+    /// The connection to the server has been lost
+    Disconnected,
+    /// The client is trying to reconnect to the server
+    Reconnecting,
+    /// The client successfully reconnected to the server
+    Reconnected,
+    /// The reconnection attempt failed
+    ReconnectFailed,
+    /// The server refused the connection because it reached its maximum number of connections
+    MaxConnectionsExceeded,
+}
+
+/// Timestamped connection event, as kept in the recent events log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatsEventRecord {
+    /// The event that occured
+    pub event: NatsEvent,
+    /// When the event occured
+    pub at: SystemTime,
+}
+
+/// Counters of every event that occured on the connection, including the ones coalesced by the debounce
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NatsEventStats {
+    /// Number of `Disconnected` events
+    pub disconnected: u64,
+    /// Number of `Reconnecting` events
+    pub reconnecting: u64,
+    /// Number of `Reconnected` events
+    pub reconnected: u64,
+    /// Number of `ReconnectFailed` events
+    pub reconnect_failed: u64,
+    /// Number of `MaxConnectionsExceeded` events
+    pub max_connections_exceeded: u64,
+}
+
+impl NatsEventStats {
+    fn record(&mut self, event: NatsEvent) {
+        match event {
+            NatsEvent::Disconnected => self.disconnected += 1,
+            NatsEvent::Reconnecting => self.reconnecting += 1,
+            NatsEvent::Reconnected => self.reconnected += 1,
+            NatsEvent::ReconnectFailed => self.reconnect_failed += 1,
+            NatsEvent::MaxConnectionsExceeded => self.max_connections_exceeded += 1,
+        }
+    }
+}
+
+/// Holds back state transitions until they persisted for the whole debounce window, so that a flurry of
+/// transient disconnects ends up as a single event. A flurry ending in the state that was already dispatched
+/// isn't a transition at all, and is dropped
+#[derive(Debug, Clone)]
+pub(crate) struct EventDebouncer {
+    window: Duration,
+    pending: Option<(NatsEvent, Instant)>,
+    last_dispatched: Option<NatsEvent>,
+}
+
+impl EventDebouncer {
+    pub(crate) fn new(window: Duration) -> Self {
+        EventDebouncer {
+            window,
+            pending: None,
+            last_dispatched: None,
+        }
+    }
+
+    /// Records a transition, replacing any transition that isn't stable yet
+    pub(crate) fn push(&mut self, event: NatsEvent, now: Instant) {
+        self.pending = Some((event, now));
+    }
+
+    /// Returns the pending event if it persisted beyond the debounce window and differs from the last one
+    /// returned
+    pub(crate) fn poll_stable(&mut self, now: Instant) -> Option<NatsEvent> {
+        let is_stable = match self.pending {
+            Some((_, at)) => now.duration_since(at) >= self.window,
+            None => false,
+        };
+
+        if !is_stable {
+            return None;
+        }
+
+        let event = self.pending.take().map(|(event, _)| event);
+        if event == self.last_dispatched {
+            return None;
+        }
+
+        self.last_dispatched = event;
+        event
+    }
+}
+
+/// Bounded log of the most recent events, so that late listeners can see what happened before they attached
+#[derive(Debug, Default, Clone)]
+pub(crate) struct EventHistory {
+    capacity: usize,
+    records: VecDeque<NatsEventRecord>,
+}
+
+impl EventHistory {
+    pub(crate) fn new(capacity: usize) -> Self {
+        EventHistory {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Appends a record, evicting the oldest one if the log is full
+    pub(crate) fn push(&mut self, record: NatsEventRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+
+        self.records.push_back(record);
+    }
+
+    /// Returns the records from the oldest to the most recent
+    pub(crate) fn snapshot(&self) -> Vec<NatsEventRecord> {
+        self.records.iter().cloned().collect()
+    }
+}
+
+/// Dispatches connection events to the registered listeners, going through the debouncer if configured
+#[derive(Debug, Default)]
+pub(crate) struct NatsEventEmitter {
+    listeners: Arc<RwLock<Vec<mpsc::UnboundedSender<NatsEvent>>>>,
+    debouncer: Option<Arc<RwLock<EventDebouncer>>>,
+    stats: RwLock<NatsEventStats>,
+    history: RwLock<EventHistory>,
+}
+
+impl NatsEventEmitter {
+    pub(crate) fn new(debounce: Option<Duration>, history_size: usize) -> Self {
+        NatsEventEmitter {
+            debouncer: debounce.map(|window| Arc::new(RwLock::new(EventDebouncer::new(window)))),
+            history: RwLock::new(EventHistory::new(history_size)),
+            ..Default::default()
+        }
+    }
+
+    /// Registers a new listener
+    pub(crate) fn listen(&self) -> mpsc::UnboundedReceiver<NatsEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.listeners.write().push(tx);
+        rx
+    }
+
+    /// Returns a snapshot of the event counters
+    pub(crate) fn stats(&self) -> NatsEventStats {
+        *self.stats.read()
+    }
+
+    /// Returns a snapshot of the most recent events
+    pub(crate) fn recent(&self) -> Vec<NatsEventRecord> {
+        self.history.read().snapshot()
+    }
+
+    /// Records an event and forwards it to the listeners, either right away or once it has been stable for the
+    /// whole debounce window
+    pub(crate) fn emit(&self, event: NatsEvent) {
+        self.stats.write().record(event);
+        self.history.write().push(NatsEventRecord {
+            event,
+            at: SystemTime::now(),
+        });
+
+        if let Some(ref debouncer) = self.debouncer {
+            let now = Instant::now();
+            let window = {
+                let mut debouncer = debouncer.write();
+                debouncer.push(event, now);
+                debouncer.window
+            };
+
+            let debouncer = Arc::clone(debouncer);
+            let listeners = Arc::clone(&self.listeners);
+            tokio_executor::spawn(Delay::new(now + window).map_err(|_| ()).map(move |_| {
+                if let Some(event) = debouncer.write().poll_stable(Instant::now()) {
+                    Self::dispatch(&listeners, event);
+                }
+            }));
+        } else {
+            Self::dispatch(&self.listeners, event);
+        }
+    }
+
+    fn dispatch(listeners: &RwLock<Vec<mpsc::UnboundedSender<NatsEvent>>>, event: NatsEvent) {
+        debug!(target: "nitox", "Dispatching connection event {:?}", event);
+        listeners.write().retain(|tx| tx.unbounded_send(event).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventDebouncer, EventHistory, NatsEvent, NatsEventEmitter, NatsEventRecord};
+    use futures::{future, prelude::*};
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant, SystemTime},
+    };
+    use tokio::runtime::Runtime;
+
+    /// Emits each group of events in a row on a runtime, waiting for the debounce window to elapse between groups,
+    /// and returns everything the listener got
+    fn emit_debounced(window: Duration, groups: Vec<Vec<NatsEvent>>) -> Vec<NatsEvent> {
+        let mut runtime = Runtime::new().unwrap();
+        let emitter = Arc::new(NatsEventEmitter::new(Some(window), 0));
+        let rx = emitter.listen();
+
+        for events in groups {
+            let group_emitter = Arc::clone(&emitter);
+            runtime
+                .block_on(future::lazy(move || {
+                    for event in &events {
+                        group_emitter.emit(*event);
+                    }
+
+                    Ok::<(), ()>(())
+                })).unwrap();
+            thread::sleep(window * 3);
+        }
+
+        // Waits for every pending debounce task, which hold the listeners as well
+        runtime.shutdown_on_idle().wait().unwrap();
+        drop(emitter);
+        rx.collect().wait().unwrap()
+    }
+
+    #[test]
+    fn it_coalesces_flaps_within_window() {
+        let start = Instant::now();
+        let mut debouncer = EventDebouncer::new(Duration::from_millis(100));
+
+        debouncer.push(NatsEvent::Reconnecting, start);
+        debouncer.push(NatsEvent::Reconnected, start + Duration::from_millis(10));
+        debouncer.push(NatsEvent::Reconnecting, start + Duration::from_millis(20));
+        debouncer.push(NatsEvent::Reconnected, start + Duration::from_millis(30));
+
+        assert_eq!(debouncer.poll_stable(start + Duration::from_millis(50)), None);
+        assert_eq!(
+            debouncer.poll_stable(start + Duration::from_millis(130)),
+            Some(NatsEvent::Reconnected)
+        );
+        assert_eq!(debouncer.poll_stable(start + Duration::from_millis(500)), None);
+    }
+
+    #[test]
+    fn it_ignores_flaps_back_to_the_dispatched_state() {
+        let start = Instant::now();
+        let mut debouncer = EventDebouncer::new(Duration::from_millis(100));
+
+        debouncer.push(NatsEvent::Reconnected, start);
+        assert_eq!(
+            debouncer.poll_stable(start + Duration::from_millis(100)),
+            Some(NatsEvent::Reconnected)
+        );
+
+        debouncer.push(NatsEvent::Disconnected, start + Duration::from_millis(200));
+        debouncer.push(NatsEvent::Reconnected, start + Duration::from_millis(210));
+        assert_eq!(debouncer.poll_stable(start + Duration::from_millis(400)), None);
+    }
+
+    #[test]
+    fn it_dispatches_a_single_event_for_rapid_flaps() {
+        let events = emit_debounced(
+            Duration::from_millis(50),
+            vec![vec![
+                NatsEvent::Disconnected,
+                NatsEvent::Reconnecting,
+                NatsEvent::Reconnected,
+                NatsEvent::Disconnected,
+                NatsEvent::Reconnecting,
+                NatsEvent::Reconnected,
+            ]],
+        );
+
+        assert_eq!(events, vec![NatsEvent::Reconnected]);
+    }
+
+    #[test]
+    fn it_dispatches_nothing_for_flaps_back_to_the_same_state() {
+        let events = emit_debounced(
+            Duration::from_millis(50),
+            vec![
+                vec![NatsEvent::Reconnected],
+                vec![NatsEvent::Disconnected, NatsEvent::Reconnecting, NatsEvent::Reconnected],
+                vec![NatsEvent::Disconnected],
+            ],
+        );
+
+        assert_eq!(events, vec![NatsEvent::Reconnected, NatsEvent::Disconnected]);
+    }
+
+    #[test]
+    fn it_emits_right_away_without_debounce() {
+        let emitter = NatsEventEmitter::new(None, 0);
+        let rx = emitter.listen();
+
+        emitter.emit(NatsEvent::Reconnecting);
+        emitter.emit(NatsEvent::Reconnected);
+        assert_eq!(emitter.stats().reconnecting, 1);
+        assert_eq!(emitter.stats().reconnected, 1);
+        drop(emitter);
+
+        let events: Vec<NatsEvent> = rx.collect().wait().unwrap();
+        assert_eq!(events, vec![NatsEvent::Reconnecting, NatsEvent::Reconnected]);
+    }
+
+    #[test]
+    fn it_keeps_events_fired_before_listening() {
+        let emitter = NatsEventEmitter::new(None, 8);
+        emitter.emit(NatsEvent::Disconnected);
+        emitter.emit(NatsEvent::Reconnecting);
+        emitter.emit(NatsEvent::Reconnected);
+
+        let _rx = emitter.listen();
+        let recent: Vec<NatsEvent> = emitter.recent().into_iter().map(|r| r.event).collect();
+        assert_eq!(
+            recent,
+            vec![NatsEvent::Disconnected, NatsEvent::Reconnecting, NatsEvent::Reconnected]
+        );
+    }
+
+    #[test]
+    fn it_bounds_the_history() {
+        let mut history = EventHistory::new(2);
+        for event in &[NatsEvent::Disconnected, NatsEvent::Reconnecting, NatsEvent::ReconnectFailed] {
+            history.push(NatsEventRecord {
+                event: *event,
+                at: SystemTime::now(),
+            });
+        }
+
+        let recent: Vec<NatsEvent> = history.snapshot().into_iter().map(|r| r.event).collect();
+        assert_eq!(recent, vec![NatsEvent::Reconnecting, NatsEvent::ReconnectFailed]);
+    }
+}
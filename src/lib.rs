@@ -96,9 +96,13 @@ extern crate native_tls;
 extern crate tokio_codec;
 extern crate tokio_executor;
 extern crate tokio_tcp;
+extern crate tokio_timer;
 extern crate tokio_tls;
 extern crate url;
 
+#[cfg(test)]
+extern crate tokio;
+
 #[macro_use]
 mod error;
 
@@ -111,7 +115,14 @@ pub mod codec;
 mod protocol;
 pub use self::protocol::*;
 
+mod events;
+pub use self::events::{NatsEvent, NatsEventRecord, NatsEventStats};
+
+mod rate_limit;
+pub use self::rate_limit::NatsRateLimit;
+
 pub(crate) mod net;
+pub use self::net::{NatsBackoff, NatsNonceSigner, TlsInfo};
 
 mod client;
 pub use self::client::*;
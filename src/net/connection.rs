@@ -1,22 +1,41 @@
 use futures::{
-    future::{self, Either},
+    future::{self, Either, Loop},
     prelude::*,
 };
 use parking_lot::RwLock;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio_executor;
+use tokio_tcp::TcpStream;
+use tokio_timer::Delay;
+use tokio_tls::TlsStream;
 
 use error::NatsError;
-use protocol::Op;
+use events::{NatsEvent, NatsEventEmitter};
+use protocol::{
+    commands::{ConnectCommand, ServerInfo, SubCommand, UnsubCommand},
+    CommandError, Op,
+};
 
 use super::connection_inner::NatsConnectionInner;
 
 macro_rules! reco {
     ($conn:ident) => {
+        reco!($conn, None)
+    };
+    ($conn:ident, $delay:expr) => {
         *$conn.state.write() = NatsConnectionState::Disconnected;
+        $conn.events.emit(NatsEvent::Disconnected);
 
-        tokio_executor::spawn($conn.reconnect().map_err(|e| {
+        let events = Arc::clone(&$conn.events);
+        tokio_executor::spawn($conn.reconnect($delay).map_err(move |e| {
             debug!(target: "nitox", "Reconnection error: {}", e);
+            events.emit(NatsEvent::ReconnectFailed);
             ()
         }));
     };
@@ -28,6 +47,278 @@ pub(crate) enum NatsConnectionState {
     Connected,
     Reconnecting,
     Disconnected,
+    /// Closed on purpose by the client, or refused by a full server before it served the connection; Never
+    /// reconnected
+    Closed,
+}
+
+/// Signs the nonce sent by the server in its INFO for NKEY/creds authentication. It is called on every
+/// (re)connection since the server generates a new nonce for each of them
+#[derive(Clone)]
+pub struct NatsNonceSigner(Arc<dyn Fn(&[u8]) -> Result<String, NatsError> + Send + Sync>);
+
+impl NatsNonceSigner {
+    pub fn new<F>(signer: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<String, NatsError> + Send + Sync + 'static,
+    {
+        NatsNonceSigner(Arc::new(signer))
+    }
+
+    /// Signs the given nonce, returning the signature to put in the CONNECT command
+    pub fn sign(&self, nonce: &[u8]) -> Result<String, NatsError> {
+        (self.0)(nonce)
+    }
+}
+
+impl fmt::Debug for NatsNonceSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("NatsNonceSigner")
+    }
+}
+
+/// Details about the TLS session of a connection. `native-tls` exposes neither the negotiated protocol version
+/// nor the cipher suite, so only the certificate presented by the server is reported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// DER-encoded certificate presented by the server, which an X.509 parser can read the subject from
+    pub peer_certificate: Option<Vec<u8>>,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_stream(stream: &TlsStream<TcpStream>) -> Self {
+        let peer_certificate = stream
+            .get_ref()
+            .peer_certificate()
+            .ok()
+            .and_then(|maybe_cert| maybe_cert)
+            .and_then(|cert| cert.to_der().ok());
+
+        TlsInfo { peer_certificate }
+    }
+}
+
+/// Exponential backoff applied to the reconnections refused by a server that reached its maximum number of
+/// connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatsBackoff {
+    /// Delay before the first retry
+    pub initial: Duration,
+    /// Upper bound of the delay, which doubles after each refusal
+    pub max: Duration,
+}
+
+/// Circuit breaker tripped each time a full server refuses the connection, so that the client backs off instead
+/// of hammering it. Reset as soon as the server actually serves the connection
+#[derive(Debug)]
+pub(crate) struct MaxConnectionsBreaker {
+    backoff: NatsBackoff,
+    delay: RwLock<Option<Duration>>,
+}
+
+impl MaxConnectionsBreaker {
+    pub(crate) fn new(backoff: NatsBackoff) -> Self {
+        MaxConnectionsBreaker {
+            backoff,
+            delay: RwLock::new(None),
+        }
+    }
+
+    /// Records a refusal, returning how long to wait before connecting again
+    pub(crate) fn trip(&self) -> Duration {
+        let mut delay = self.delay.write();
+        let next = match *delay {
+            Some(previous) => previous
+                .checked_mul(2)
+                .map_or(self.backoff.max, |doubled| ::std::cmp::min(doubled, self.backoff.max)),
+            None => self.backoff.initial,
+        };
+
+        *delay = Some(next);
+        next
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.delay.write() = None;
+    }
+}
+
+/// Subscription restored after a reconnection
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ActiveSubscription {
+    pub(crate) cmd: SubCommand,
+    /// Messages left before the auto-unsubscription, if the subscription got limited through `UNSUB`
+    pub(crate) max_msgs: Option<u32>,
+}
+
+impl From<SubCommand> for ActiveSubscription {
+    fn from(cmd: SubCommand) -> Self {
+        ActiveSubscription { cmd, max_msgs: None }
+    }
+}
+
+/// Handshake replayed on the new socket after a reconnection
+#[derive(Debug, Default)]
+pub(crate) struct NatsHandshake {
+    /// CONNECT command previously sent by the client, if any. Always stored unsigned
+    pub(crate) connect_command: RwLock<Option<ConnectCommand>>,
+    /// Sends CONNECT right after the socket is up instead of waiting for INFO; Only honored on plaintext connections
+    pub(crate) pipeline_connect: bool,
+    /// Signer of the server nonce, if using NKEY/creds authentication
+    pub(crate) nonce_signer: Option<NatsNonceSigner>,
+    /// Latest INFO received from the server
+    pub(crate) server_info: Arc<RwLock<Option<ServerInfo>>>,
+    /// Active subscriptions by sid, sent again after CONNECT along with their pending limit
+    pub(crate) subscriptions: RwLock<HashMap<String, ActiveSubscription>>,
+    /// Details of the latest TLS handshake; `None` on plaintext connections
+    pub(crate) tls_info: RwLock<Option<TlsInfo>>,
+    /// Backs off reconnections refused by a full server; Without it, such a refusal fails the reconnection
+    pub(crate) max_connections_breaker: Option<MaxConnectionsBreaker>,
+}
+
+impl NatsHandshake {
+    /// Tells if CONNECT can be pipelined without waiting for INFO. TLS connections and nonce signing always need INFO
+    pub(crate) fn should_pipeline_connect(&self, is_tls: bool) -> bool {
+        self.pipeline_connect && !is_tls && self.nonce_signer.is_none()
+    }
+
+    /// Signs the CONNECT command against the nonce of the given INFO. Any previous signature is discarded as it
+    /// would be rejected on a new connection
+    pub(crate) fn sign_connect(
+        &self,
+        mut cmd: ConnectCommand,
+        info: Option<&ServerInfo>,
+    ) -> Result<ConnectCommand, NatsError> {
+        cmd.sig = match (&self.nonce_signer, info.and_then(|info| info.nonce.as_ref())) {
+            (Some(signer), Some(nonce)) => Some(signer.sign(nonce.as_bytes())?),
+            _ => None,
+        };
+
+        Ok(cmd)
+    }
+}
+
+/// Sends CONNECT and PING followed by the SUB of every active subscription as a single batch, so that they're all
+/// encoded before the connection gets flushed. Limited subscriptions get their UNSUB sent again right after their
+/// SUB, with the number of messages they still expect. The PING goes before the SUBs so that no MSG can come
+/// before its PONG
+fn send_connect_batch<S>(
+    conn: S,
+    connect_cmd: ConnectCommand,
+    hs: &NatsHandshake,
+) -> impl Future<Item = S, Error = NatsError>
+where
+    S: Sink<SinkItem = Op, SinkError = NatsError>,
+{
+    let subscriptions = hs.subscriptions.read();
+    let mut ops = Vec::with_capacity(subscriptions.len() + 2);
+    ops.push(Op::CONNECT(connect_cmd));
+    ops.push(Op::PING);
+    for active in subscriptions.values() {
+        ops.push(Op::SUB(active.cmd.clone()));
+        if let Some(max_msgs) = active.max_msgs {
+            ops.push(Op::UNSUB(UnsubCommand {
+                sid: active.cmd.sid.clone(),
+                max_msgs: Some(max_msgs),
+            }));
+        }
+    }
+    debug!(target: "nitox", "Sending CONNECT along with {} subscriptions", subscriptions.len());
+    drop(subscriptions);
+
+    // Unlike `send_all`, never closes the sink, which would shut the new socket down
+    let mut pending: VecDeque<Op> = ops.into();
+    let mut conn = Some(conn);
+    future::poll_fn(move || {
+        {
+            let sink = conn.as_mut().expect("Batch polled after completion");
+            while let Some(op) = pending.pop_front() {
+                if let AsyncSink::NotReady(op) = sink.start_send(op)? {
+                    pending.push_front(op);
+                    if sink.poll_complete()?.is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+
+            if sink.poll_complete()?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(Async::Ready(conn.take().expect("Batch polled after completion")))
+    })
+}
+
+/// Waits for the PONG answering the PING sent along with CONNECT, which tells that the server actually serves the
+/// connection. A full server sends its INFO before refusing the connection, so the refusal may come after INFO,
+/// whether CONNECT got through or not. Any other -ERR, such as an authorization violation, fails the handshake too
+fn wait_for_pong<S>(conn: S, hs: Arc<NatsHandshake>) -> impl Future<Item = S, Error = NatsError>
+where
+    S: Stream<Item = Op, Error = NatsError>,
+{
+    future::loop_fn(conn, move |conn| {
+        let hs = Arc::clone(&hs);
+        conn.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(maybe_op, conn)| match maybe_op {
+                Some(Op::PONG) => Ok(Loop::Break(conn)),
+                Some(Op::ERR(err)) => Err(err.into()),
+                // Comes first when CONNECT got pipelined
+                Some(Op::INFO(info)) => {
+                    *hs.server_info.write() = Some(info);
+                    Ok(Loop::Continue(conn))
+                }
+                Some(op) => {
+                    debug!(target: "nitox", "Skipping {:?} while waiting for PONG", op);
+                    Ok(Loop::Continue(conn))
+                }
+                None => Err(NatsError::ServerDisconnected(None)),
+            })
+    })
+}
+
+/// Performs the client side of the handshake on a freshly opened connection: either sends CONNECT right away
+/// when pipelining, or waits for a fresh server INFO and sends CONNECT signed against its nonce. Active
+/// subscriptions are restored right after, and the handshake only succeeds once the server answered the PING
+/// sent along with CONNECT
+pub(crate) fn handshake<S>(
+    conn: S,
+    hs: Arc<NatsHandshake>,
+    is_tls: bool,
+) -> impl Future<Item = S, Error = NatsError>
+where
+    S: Sink<SinkItem = Op, SinkError = NatsError> + Stream<Item = Op, Error = NatsError>,
+{
+    let connect_cmd = match hs.connect_command.read().clone() {
+        Some(cmd) => cmd,
+        None => return Either::A(future::ok(conn)),
+    };
+
+    if hs.should_pipeline_connect(is_tls) {
+        debug!(target: "nitox", "Pipelining CONNECT without waiting for INFO");
+        return Either::B(Either::A(
+            send_connect_batch(conn, connect_cmd, &hs).and_then(move |conn| wait_for_pong(conn, hs)),
+        ));
+    }
+
+    Either::B(Either::B(conn.into_future().map_err(|(e, _)| e).and_then(
+        move |(maybe_op, conn)| match maybe_op {
+            Some(Op::INFO(info)) => {
+                debug!(target: "nitox", "Got INFO, sending CONNECT");
+                let signed_cmd = hs.sign_connect(connect_cmd, Some(&info));
+                *hs.server_info.write() = Some(info);
+                Either::A(
+                    future::result(signed_cmd)
+                        .and_then(move |cmd| send_connect_batch(conn, cmd, &hs).map(move |conn| (conn, hs)))
+                        .and_then(|(conn, hs)| wait_for_pong(conn, hs)),
+                )
+            }
+            Some(Op::ERR(err)) => Either::B(future::err(err.into())),
+            Some(_) => Either::B(future::err(CommandError::CommandMalformed.into())),
+            None => Either::B(future::err(NatsError::ServerDisconnected(None))),
+        },
+    )))
 }
 
 /// Represents a connection to a NATS server. Implements `Sink` and `Stream`
@@ -43,37 +334,97 @@ pub struct NatsConnection {
     pub(crate) inner: Arc<RwLock<NatsConnectionInner>>,
     /// Current state of the connection
     pub(crate) state: Arc<RwLock<NatsConnectionState>>,
+    /// Emitter of the connection lifecycle events
+    pub(crate) events: Arc<NatsEventEmitter>,
+    /// Handshake to replay after reconnecting
+    pub(crate) handshake: Arc<NatsHandshake>,
 }
 
 impl NatsConnection {
-    /// Tries to reconnect once to the server; Only used internally. Blocks polling during reconnecting
-    /// by forcing the object to return `Async::NotReady`/`AsyncSink::NotReady`
-    fn reconnect(&self) -> impl Future<Item = (), Error = NatsError> {
+    /// Tries to reconnect to the server, after waiting for `delay` if any; Only used internally. Blocks polling
+    /// during reconnecting by forcing the object to return `Async::NotReady`/`AsyncSink::NotReady`. Refusals from a
+    /// full server are retried with backoff if the circuit breaker is configured, any other failure is final
+    fn reconnect(&self, delay: Option<Duration>) -> impl Future<Item = (), Error = NatsError> {
         *self.state.write() = NatsConnectionState::Reconnecting;
+        self.events.emit(NatsEvent::Reconnecting);
 
-        let inner_arc = Arc::clone(&self.inner);
-        let inner_state = Arc::clone(&self.state);
+        let addr = self.addr;
         let is_tls = self.is_tls;
         let maybe_host = self.host.clone();
-        NatsConnectionInner::connect_tcp(&self.addr)
+        let inner_arc = Arc::clone(&self.inner);
+        let inner_state = Arc::clone(&self.state);
+        let events = Arc::clone(&self.events);
+        let hs = Arc::clone(&self.handshake);
+
+        future::loop_fn(delay, move |delay| {
+            let wait = match delay {
+                Some(delay) => Either::A(
+                    Delay::new(Instant::now() + delay).map_err(|e| NatsError::GenericError(e.to_string())),
+                ),
+                None => Either::B(future::ok(())),
+            };
+
+            let maybe_host = maybe_host.clone();
+            let attempt_hs = Arc::clone(&hs);
+            let inner_arc = Arc::clone(&inner_arc);
+            let inner_state = Arc::clone(&inner_state);
+            let events = Arc::clone(&events);
+            let hs = Arc::clone(&hs);
+            wait.and_then(move |_| Self::connect_and_handshake(addr, maybe_host, is_tls, attempt_hs))
+                .then(move |res| match res {
+                    Ok(inner) => {
+                        {
+                            *inner_arc.write() = inner;
+                            *inner_state.write() = NatsConnectionState::Connected;
+                        }
+                        // The server answered the handshake PING, so it actually serves the connection
+                        if let Some(ref breaker) = hs.max_connections_breaker {
+                            breaker.reset();
+                        }
+                        events.emit(NatsEvent::Reconnected);
+                        debug!(target: "nitox", "Successfully swapped reconnected underlying connection");
+                        Ok(Loop::Break(()))
+                    }
+                    Err(NatsError::MaxConnectionsExceeded) => {
+                        events.emit(NatsEvent::MaxConnectionsExceeded);
+                        match hs.max_connections_breaker {
+                            Some(ref breaker) => {
+                                let delay = breaker.trip();
+                                debug!(target: "nitox", "Server is full, reconnecting in {:?}", delay);
+                                Ok(Loop::Continue(Some(delay)))
+                            }
+                            None => Err(NatsError::MaxConnectionsExceeded),
+                        }
+                    }
+                    Err(e) => Err(e),
+                })
+        })
+    }
+
+    /// Opens a new socket to the server, upgraded to TLS if needed, and performs the handshake on it
+    fn connect_and_handshake(
+        addr: SocketAddr,
+        maybe_host: Option<String>,
+        is_tls: bool,
+        hs: Arc<NatsHandshake>,
+    ) -> impl Future<Item = NatsConnectionInner, Error = NatsError> {
+        let tls_hs = Arc::clone(&hs);
+        NatsConnectionInner::connect_tcp(&addr)
             .and_then(move |socket| {
                 if is_tls {
                     Either::A(
                         // This unwrap is safe because the value would always be present if `is_tls` is true
-                        NatsConnectionInner::upgrade_tcp_to_tls(&maybe_host.unwrap(), socket)
-                            .map(NatsConnectionInner::from),
+                        NatsConnectionInner::upgrade_tcp_to_tls(&maybe_host.unwrap(), socket).map(
+                            move |socket| {
+                                *tls_hs.tls_info.write() = Some(TlsInfo::from_stream(&socket));
+                                NatsConnectionInner::from(socket)
+                            },
+                        ),
                     )
                 } else {
                     Either::B(future::ok(NatsConnectionInner::from(socket)))
                 }
-            }).and_then(move |inner| {
-                {
-                    *inner_arc.write() = inner;
-                    *inner_state.write() = NatsConnectionState::Connected;
-                }
-                debug!(target: "nitox", "Successfully swapped reconnected underlying connection");
-                Ok(())
-            })
+            }).and_then(move |inner| handshake(inner, hs, is_tls))
     }
 }
 
@@ -82,6 +433,10 @@ impl Sink for NatsConnection {
     type SinkItem = Op;
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if let Some(NatsConnectionState::Closed) = self.state.try_read().map(|state| *state) {
+            return Err(NatsError::ServerDisconnected(None));
+        }
+
         if match self.state.try_read() {
             Some(state) => *state != NatsConnectionState::Connected,
             _ => true,
@@ -103,6 +458,10 @@ impl Sink for NatsConnection {
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if let Some(NatsConnectionState::Closed) = self.state.try_read().map(|state| *state) {
+            return Err(NatsError::ServerDisconnected(None));
+        }
+
         if match self.state.try_read() {
             Some(state) => *state != NatsConnectionState::Connected,
             _ => true,
@@ -122,6 +481,30 @@ impl Sink for NatsConnection {
             Ok(Async::NotReady)
         }
     }
+
+    /// Flushes everything that was accepted, then shuts the socket down for good
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        match self.state.try_read().map(|state| *state) {
+            Some(NatsConnectionState::Connected) => {}
+            Some(NatsConnectionState::Closed) => return Ok(Async::Ready(())),
+            // The accepted OPs went away with the previous socket, there is nothing left to flush
+            Some(_) => return Err(NatsError::ServerDisconnected(None)),
+            None => return Ok(Async::NotReady),
+        }
+
+        let closed = if let Some(mut inner) = self.inner.try_write() {
+            inner.close()?
+        } else {
+            Async::NotReady
+        };
+
+        if closed.is_ready() {
+            debug!(target: "nitox", "Connection closed after flushing everything");
+            *self.state.write() = NatsConnectionState::Closed;
+        }
+
+        Ok(closed)
+    }
 }
 
 impl Stream for NatsConnection {
@@ -129,6 +512,10 @@ impl Stream for NatsConnection {
     type Item = Op;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(NatsConnectionState::Closed) = self.state.try_read().map(|state| *state) {
+            return Ok(Async::Ready(None));
+        }
+
         if match self.state.try_read() {
             Some(state) => *state != NatsConnectionState::Connected,
             _ => true,
@@ -142,6 +529,22 @@ impl Stream for NatsConnection {
                     reco!(self);
                     Ok(Async::NotReady)
                 }
+                Ok(Async::Ready(Some(op))) => {
+                    // A full server sends its INFO before refusing the connection and closing it. Reconnections go
+                    // through their own handshake, so this only happens while the client waits for its first CONNECT
+                    // to be answered: the ERR is forwarded for it to report the refusal, and the connection ends
+                    // right after instead of backing off on behalf of a client that gave up
+                    if let Op::ERR(ref err) = op {
+                        if err.is_max_connections_exceeded() {
+                            debug!(target: "nitox", "Server refused the connection: {}", err);
+                            self.events.emit(NatsEvent::MaxConnectionsExceeded);
+                            *self.state.write() = NatsConnectionState::Closed;
+                            self.events.emit(NatsEvent::Disconnected);
+                        }
+                    }
+
+                    Ok(Async::Ready(Some(op)))
+                }
                 poll_res => poll_res,
             }
         } else {
@@ -149,3 +552,312 @@ impl Stream for NatsConnection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        handshake, ActiveSubscription, MaxConnectionsBreaker, NatsBackoff, NatsHandshake, NatsNonceSigner, TlsInfo,
+    };
+    use error::NatsError;
+    use futures::prelude::*;
+    use native_tls::{Identity, TlsAcceptor as NativeTlsAcceptor, TlsConnector as NativeTlsConnector};
+    use parking_lot::RwLock;
+    use protocol::{commands::*, Op};
+    use std::{collections::VecDeque, sync::Arc, time::Duration};
+    use tokio::runtime::Runtime;
+    use tokio_tcp::{TcpListener, TcpStream};
+    use tokio_tls::{TlsAcceptor, TlsConnector};
+
+    /// Fake connection logging everything that goes through it
+    #[derive(Debug, Default)]
+    struct MockConnection {
+        incoming: VecDeque<Op>,
+        sent: Vec<Op>,
+        log: Vec<String>,
+        flushes: usize,
+    }
+
+    impl Sink for MockConnection {
+        type SinkError = NatsError;
+        type SinkItem = Op;
+
+        fn start_send(&mut self, item: Op) -> StartSend<Op, NatsError> {
+            self.log.push("sent".into());
+            self.sent.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), NatsError> {
+            self.flushes += 1;
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl Stream for MockConnection {
+        type Error = NatsError;
+        type Item = Op;
+
+        fn poll(&mut self) -> Poll<Option<Op>, NatsError> {
+            let op = self.incoming.pop_front();
+            self.log.push(format!("received {:?}", op.is_some()));
+            Ok(Async::Ready(op))
+        }
+    }
+
+    fn server_info(nonce: Option<&str>) -> Op {
+        Op::INFO(
+            ServerInfo::builder()
+                .server_id("nitox-test")
+                .version("1.3.0")
+                .go("go1.10.3")
+                .host("127.0.0.1")
+                .port(4222u32)
+                .max_payload(4000u32)
+                .nonce(nonce.map(|n| n.to_string()))
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn new_handshake(pipeline_connect: bool, nonce_signer: Option<NatsNonceSigner>) -> Arc<NatsHandshake> {
+        Arc::new(NatsHandshake {
+            connect_command: RwLock::new(Some(ConnectCommand::builder().build().unwrap())),
+            pipeline_connect,
+            nonce_signer,
+            ..Default::default()
+        })
+    }
+
+    fn test_signer() -> NatsNonceSigner {
+        NatsNonceSigner::new(|nonce| Ok(format!("signed:{}", String::from_utf8_lossy(nonce))))
+    }
+
+    #[test]
+    fn it_pipelines_connect_on_plaintext_only() {
+        let hs = new_handshake(true, None);
+        assert!(hs.should_pipeline_connect(false));
+        assert!(!hs.should_pipeline_connect(true));
+        assert!(!new_handshake(false, None).should_pipeline_connect(false));
+        assert!(!new_handshake(true, Some(test_signer())).should_pipeline_connect(false));
+    }
+
+    #[test]
+    fn it_sends_connect_without_waiting_for_info() {
+        let hs = new_handshake(true, None);
+        let mut conn = MockConnection::default();
+        conn.incoming.push_back(server_info(None));
+        conn.incoming.push_back(Op::PONG);
+
+        let conn = handshake(conn, Arc::clone(&hs), false).wait().unwrap();
+        assert_eq!(
+            conn.log,
+            vec!["sent", "sent", "received true", "received true"]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(conn.sent[1], Op::PING);
+        assert_eq!(conn.flushes, 1);
+        // The INFO read while waiting for PONG is kept
+        assert!(hs.server_info.read().is_some());
+    }
+
+    #[test]
+    fn it_waits_for_info_before_connect() {
+        let mut conn = MockConnection::default();
+        conn.incoming.push_back(server_info(None));
+        conn.incoming.push_back(Op::PONG);
+
+        let conn = handshake(conn, new_handshake(true, None), true).wait().unwrap();
+        assert_eq!(
+            conn.log,
+            vec!["received true", "sent", "sent", "received true"]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_waits_for_pong() {
+        let mut conn = MockConnection::default();
+        conn.incoming.push_back(server_info(None));
+        conn.incoming.push_back(Op::OK);
+
+        match handshake(conn, new_handshake(false, None), false).wait() {
+            Err(NatsError::ServerDisconnected(None)) => {}
+            other => panic!("Expected a disconnection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_fails_if_server_closes_before_info() {
+        match handshake(MockConnection::default(), new_handshake(false, None), false).wait() {
+            Err(NatsError::ServerDisconnected(None)) => {}
+            other => panic!("Expected a disconnection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_maps_max_connections_exceeded_and_backs_off() {
+        // A full server sends its INFO first, then refuses the connection whether CONNECT was pipelined or not
+        for &pipeline_connect in &[false, true] {
+            let mut conn = MockConnection::default();
+            conn.incoming.push_back(server_info(None));
+            conn.incoming.push_back(Op::ERR(ServerError::from(
+                "ERR 'Maximum Connections Exceeded'\r\n".to_string(),
+            )));
+
+            match handshake(conn, new_handshake(pipeline_connect, None), false).wait() {
+                Err(NatsError::MaxConnectionsExceeded) => {}
+                other => panic!("Expected MaxConnectionsExceeded, got {:?}", other),
+            }
+        }
+
+        let breaker = MaxConnectionsBreaker::new(NatsBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(300),
+        });
+        let delays: Vec<Duration> = (0..4).map(|_| breaker.trip()).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(300),
+                Duration::from_millis(300),
+            ]
+        );
+
+        breaker.reset();
+        assert_eq!(breaker.trip(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn it_fails_on_server_errors() {
+        for &pipeline_connect in &[false, true] {
+            let mut conn = MockConnection::default();
+            conn.incoming.push_back(server_info(None));
+            conn.incoming.push_back(Op::ERR(ServerError::from(
+                "ERR 'Authorization Violation'\r\n".to_string(),
+            )));
+            conn.incoming.push_back(Op::PONG);
+
+            match handshake(conn, new_handshake(pipeline_connect, None), false).wait() {
+                Err(NatsError::ServerError(ref msg)) => assert_eq!(msg, "'Authorization Violation'"),
+                other => panic!("Expected ServerError, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn it_signs_each_connection_nonce() {
+        let hs = new_handshake(false, Some(test_signer()));
+
+        for nonce in &["first-nonce", "second-nonce"] {
+            let mut conn = MockConnection::default();
+            conn.incoming.push_back(server_info(Some(nonce)));
+            conn.incoming.push_back(Op::PONG);
+
+            let conn = handshake(conn, Arc::clone(&hs), false).wait().unwrap();
+            match conn.sent.as_slice() {
+                [Op::CONNECT(cmd), Op::PING] => assert_eq!(cmd.sig, Some(format!("signed:{}", nonce))),
+                other => panic!("Expected CONNECT then PING, got {:?}", other),
+            }
+
+            assert_eq!(hs.server_info.read().as_ref().and_then(|i| i.nonce.clone()), Some(nonce.to_string()));
+            // The stored command is never signed, so that it can't be replayed on another connection
+            assert!(hs.connect_command.read().as_ref().unwrap().sig.is_none());
+        }
+    }
+
+    #[test]
+    fn it_restores_subscriptions_in_a_single_flush() {
+        let hs = new_handshake(false, None);
+        for i in 0..500 {
+            let cmd = SubCommand::builder().subject(format!("foo.{}", i)).build().unwrap();
+            hs.subscriptions.write().insert(cmd.sid.clone(), cmd.into());
+        }
+
+        let mut conn = MockConnection::default();
+        conn.incoming.push_back(server_info(None));
+        conn.incoming.push_back(Op::PONG);
+        let conn = handshake(conn, hs, false).wait().unwrap();
+
+        assert_eq!(conn.sent.len(), 502);
+        match conn.sent[0] {
+            Op::CONNECT(_) => {}
+            ref op => panic!("Expected CONNECT first, got {:?}", op),
+        }
+        assert_eq!(conn.sent[1], Op::PING);
+        assert!(conn.sent[2..].iter().all(|op| match op {
+            Op::SUB(_) => true,
+            _ => false,
+        }));
+        assert_eq!(conn.flushes, 1);
+    }
+
+    #[test]
+    fn it_restores_pending_unsubscription_limits() {
+        let hs = new_handshake(false, None);
+        let cmd = SubCommand::builder().subject("foo").build().unwrap();
+        let sid = cmd.sid.clone();
+        hs.subscriptions.write().insert(
+            sid.clone(),
+            ActiveSubscription {
+                cmd: cmd.clone(),
+                max_msgs: Some(3),
+            },
+        );
+
+        let mut conn = MockConnection::default();
+        conn.incoming.push_back(server_info(None));
+        conn.incoming.push_back(Op::PONG);
+        let conn = handshake(conn, hs, false).wait().unwrap();
+
+        match &conn.sent[2..] {
+            [Op::SUB(sub), Op::UNSUB(unsub)] => {
+                assert_eq!(sub, &cmd);
+                assert_eq!(unsub.sid, sid);
+                assert_eq!(unsub.max_msgs, Some(3));
+            }
+            other => panic!("Expected SUB then UNSUB, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_reports_the_server_certificate() {
+        let mut runtime = Runtime::new().unwrap();
+        let identity = Identity::from_pkcs12(include_bytes!("../../tests/fixtures/identity.p12"), "nitox").unwrap();
+        let acceptor: TlsAcceptor = NativeTlsAcceptor::new(identity).unwrap().into();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        runtime.spawn(
+            listener
+                .incoming()
+                .take(1)
+                .for_each(move |socket| acceptor.accept(socket).then(|_| Ok(())))
+                .map_err(|_| ()),
+        );
+
+        // The fixture is self-signed
+        let connector: TlsConnector = NativeTlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap()
+            .into();
+        let tls_info = runtime
+            .block_on(
+                TcpStream::connect(&addr)
+                    .from_err::<NatsError>()
+                    .and_then(move |socket| connector.connect("localhost", socket).from_err())
+                    .map(|stream| TlsInfo::from_stream(&stream)),
+            ).unwrap();
+        let _ = runtime.shutdown_now().wait();
+
+        assert_eq!(
+            tls_info.peer_certificate,
+            Some(include_bytes!("../../tests/fixtures/certificate.der").to_vec())
+        );
+    }
+}
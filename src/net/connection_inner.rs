@@ -2,7 +2,10 @@ use codec::OpCodec;
 use futures::prelude::*;
 use native_tls::TlsConnector as NativeTlsConnector;
 use protocol::Op;
-use std::net::SocketAddr;
+use std::{
+    io,
+    net::{Shutdown, SocketAddr},
+};
 use tokio_codec::{Decoder, Framed};
 use tokio_tcp::TcpStream;
 use tokio_tls::{TlsConnector, TlsStream};
@@ -66,6 +69,30 @@ impl Sink for NatsConnectionInner {
             NatsConnectionInner::Tls(framed) => framed.poll_complete(),
         }
     }
+
+    /// Flushes the pending OPs, then shuts the socket down. Shutting down a tokio `TcpStream` through the `Sink`
+    /// alone is a no-op, which would leave both ends waiting on it until it gets dropped
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        let closed = match self {
+            NatsConnectionInner::Tcp(framed) => framed.close()?,
+            NatsConnectionInner::Tls(framed) => framed.close()?,
+        };
+
+        if closed.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+
+        let socket = match self {
+            NatsConnectionInner::Tcp(framed) => framed.get_ref(),
+            NatsConnectionInner::Tls(framed) => framed.get_ref().get_ref().get_ref(),
+        };
+
+        match socket.shutdown(Shutdown::Both) {
+            // The server closed it first
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(Async::Ready(())),
+            res => res.map(Async::Ready).map_err(NatsError::from),
+        }
+    }
 }
 
 impl Stream for NatsConnectionInner {
@@ -7,14 +7,20 @@ pub(crate) mod connection;
 mod connection_inner;
 
 use error::NatsError;
+use events::NatsEventEmitter;
 
 use self::connection::NatsConnectionState;
 use self::connection_inner::*;
 
-pub(crate) use self::connection::NatsConnection;
+pub(crate) use self::connection::{ActiveSubscription, MaxConnectionsBreaker, NatsConnection, NatsHandshake};
+pub use self::connection::{NatsBackoff, NatsNonceSigner, TlsInfo};
 
 /// Connect to a raw TCP socket
-pub(crate) fn connect(addr: SocketAddr) -> impl Future<Item = NatsConnection, Error = NatsError> {
+pub(crate) fn connect(
+    addr: SocketAddr,
+    events: Arc<NatsEventEmitter>,
+    handshake: Arc<NatsHandshake>,
+) -> impl Future<Item = NatsConnection, Error = NatsError> {
     NatsConnectionInner::connect_tcp(&addr).map(move |socket| {
         debug!(target: "nitox", "Connected through TCP");
         NatsConnection {
@@ -23,12 +29,19 @@ pub(crate) fn connect(addr: SocketAddr) -> impl Future<Item = NatsConnection, Er
             host: None,
             state: Arc::new(RwLock::new(NatsConnectionState::Connected)),
             inner: Arc::new(RwLock::new(socket.into())),
+            events,
+            handshake,
         }
     })
 }
 
 /// Connect to a TLS over TCP socket. Upgrade is performed automatically
-pub(crate) fn connect_tls(host: String, addr: SocketAddr) -> impl Future<Item = NatsConnection, Error = NatsError> {
+pub(crate) fn connect_tls(
+    host: String,
+    addr: SocketAddr,
+    events: Arc<NatsEventEmitter>,
+    handshake: Arc<NatsHandshake>,
+) -> impl Future<Item = NatsConnection, Error = NatsError> {
     let inner_host = host.clone();
     NatsConnectionInner::connect_tcp(&addr)
         .and_then(move |socket| {
@@ -36,12 +49,15 @@ pub(crate) fn connect_tls(host: String, addr: SocketAddr) -> impl Future<Item =
             NatsConnectionInner::upgrade_tcp_to_tls(&host, socket)
         }).map(move |socket| {
             debug!(target: "nitox", "Connected through TCP over TLS");
+            *handshake.tls_info.write() = Some(TlsInfo::from_stream(&socket));
             NatsConnection {
                 is_tls: true,
                 addr,
                 host: Some(inner_host),
                 state: Arc::new(RwLock::new(NatsConnectionState::Connected)),
                 inner: Arc::new(RwLock::new(socket.into())),
+                events,
+                handshake,
             }
         })
 }
@@ -24,6 +24,13 @@ pub struct ConnectCommand {
     /// Connection password (if auth_required is set)
     #[serde(skip_serializing_if = "Option::is_none")]
     pass: Option<String>,
+    /// The public NKEY to authenticate with (if auth_required is set and a nonce is sent by the server)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nkey: Option<String>,
+    /// The server nonce signed by the client; Computed during the handshake of every connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(skip))]
+    pub(crate) sig: Option<String>,
     /// Optional client name
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(default = "self.default_name()?")]
@@ -39,11 +46,16 @@ pub struct ConnectCommand {
     /// with known servers it can reconnect to.
     #[serde(skip_serializing_if = "Option::is_none")]
     protocol: Option<u8>,
-    /// Optional boolean. If set to true, the server (version 1.2.0+) will not send originating messages from this
-    /// connection to its own subscriptions. Clients should set this to true only for server supporting this feature,
-    /// which is when proto in the INFO protocol is set to at least 1.
+    /// Optional boolean. If set to false, the server (version 1.2.0+) will not send originating messages from this
+    /// connection to its own subscriptions. Clients should set this to false only for server supporting this feature,
+    /// which is when proto in the INFO protocol is set to at least 1. Absent means enabled.
     #[serde(skip_serializing_if = "Option::is_none")]
     echo: Option<bool>,
+    /// Optional boolean. Tells the server (version 2.2.0+) that the client understands messages with headers,
+    /// delivered through HMSG. Required for JetStream flow control, so it's enabled by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(default = "Some(true)")]
+    pub headers: Option<bool>,
 }
 
 impl ConnectCommand {
@@ -96,7 +108,7 @@ mod tests {
     use super::{ConnectCommand, ConnectCommandBuilder};
     use protocol::Command;
 
-    static DEFAULT_CONNECT: &'static str = "CONNECT\t{\"verbose\":false,\"pedantic\":false,\"tls_required\":false,\"name\":\"nitox\",\"lang\":\"rust\",\"version\":\"1.0.0\"}\r\n";
+    static DEFAULT_CONNECT: &'static str = "CONNECT\t{\"verbose\":false,\"pedantic\":false,\"tls_required\":false,\"name\":\"nitox\",\"lang\":\"rust\",\"version\":\"1.0.0\",\"headers\":true}\r\n";
 
     #[test]
     fn it_parses() {
@@ -110,6 +122,7 @@ mod tests {
         assert_eq!(&cmd.name.unwrap(), "nitox");
         assert_eq!(&cmd.lang, "rust");
         assert_eq!(&cmd.version, "1.0.0");
+        assert_eq!(cmd.headers, Some(true));
     }
 
     #[test]
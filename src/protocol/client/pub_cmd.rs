@@ -1,5 +1,6 @@
 use bytes::{BufMut, Bytes, BytesMut};
-use protocol::{Command, CommandError};
+use error::NatsError;
+use protocol::{check_subject, Command, CommandError};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
 /// The PUB message publishes the message payload to the given subject name, optionally supplying a reply subject.
@@ -24,6 +25,30 @@ impl PubCommand {
         PubCommandBuilder::default()
     }
 
+    /// Creates a PUB command from its raw parts, ensuring the declared payload length matches the actual payload
+    /// so the frame can't be rejected by the server. The builder computes the length by itself and should be preferred.
+    pub fn from_raw_parts(
+        subject: String,
+        reply_to: Option<String>,
+        payload_len: usize,
+        payload: Bytes,
+    ) -> Result<Self, NatsError> {
+        if payload.len() != payload_len {
+            return Err(CommandError::CommandMalformed.into());
+        }
+
+        check_subject(&subject)?;
+        if let Some(ref reply_to) = reply_to {
+            check_subject(reply_to)?;
+        }
+
+        Ok(PubCommand {
+            subject,
+            reply_to,
+            payload,
+        })
+    }
+
     /// Generates a random `reply_to` `String`
     pub fn generate_reply_to() -> String {
         let mut rng = thread_rng();
@@ -122,7 +147,8 @@ impl PubCommandBuilder {
 #[cfg(test)]
 mod tests {
     use super::{PubCommand, PubCommandBuilder};
-    use protocol::Command;
+    use error::NatsError;
+    use protocol::{Command, CommandError};
 
     static DEFAULT_PUB: &'static str = "PUB\tFOO\t11\r\nHello NATS!\r\n";
 
@@ -150,4 +176,64 @@ mod tests {
 
         assert_eq!(DEFAULT_PUB, cmd_bytes);
     }
+
+    #[test]
+    fn it_always_declares_the_payload_length() {
+        for payload in &["", "a", "Hello NATS!", "multi\r\nline"] {
+            let cmd = PubCommandBuilder::default()
+                .subject("FOO")
+                .payload(*payload)
+                .build()
+                .unwrap();
+
+            let cmd_bytes = cmd.into_vec().unwrap();
+            let expected = format!("PUB\tFOO\t{}\r\n{}\r\n", payload.len(), payload);
+            assert_eq!(expected, cmd_bytes);
+
+            let parsed = PubCommand::try_parse(&cmd_bytes).unwrap();
+            assert_eq!(&parsed.payload[..], payload.as_bytes());
+        }
+    }
+
+    #[test]
+    fn it_rejects_mismatching_payload_length() {
+        let res = PubCommand::from_raw_parts("FOO".into(), None, 3, "Hello NATS!".into());
+        match res {
+            Err(NatsError::ProtocolError(CommandError::CommandMalformed)) => {}
+            other => panic!("Expected a ProtocolError, got {:?}", other),
+        }
+
+        let cmd = PubCommand::from_raw_parts("FOO".into(), None, 11, "Hello NATS!".into()).unwrap();
+        assert_eq!(DEFAULT_PUB, cmd.into_vec().unwrap());
+    }
+
+    #[test]
+    fn it_rejects_newlines_in_subject() {
+        assert!(PubCommandBuilder::default().subject("FOO\r\nBAR").build().is_err());
+        assert!(
+            PubCommandBuilder::default()
+                .subject("FOO")
+                .reply_to(Some("INBOX\n".into()))
+                .build()
+                .is_err()
+        );
+
+        match PubCommand::from_raw_parts("FOO\nBAR".into(), None, 0, "".into()) {
+            Err(NatsError::InvalidSubject(_)) => {}
+            other => panic!("Expected an InvalidSubject error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_keeps_binary_payloads_intact() {
+        let payload: &[u8] = b"\x00\xff\r\n\xc3\x28";
+        let cmd = PubCommandBuilder::default()
+            .subject("FOO")
+            .payload(payload)
+            .build()
+            .unwrap();
+
+        let parsed = PubCommand::try_parse(&cmd.into_vec().unwrap()).unwrap();
+        assert_eq!(&parsed.payload[..], payload);
+    }
 }
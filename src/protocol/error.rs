@@ -33,6 +33,24 @@ pub enum CommandError {
     GenericError(String),
 }
 
+impl CommandError {
+    /// Rebuilds the same error from a shared reference to it, keeping its variant. JSON errors, which can't be
+    /// copied, are kept as their message
+    pub(crate) fn rebuild(&self) -> Self {
+        match *self {
+            CommandError::JsonError(ref e) => CommandError::GenericError(e.to_string()),
+            CommandError::ValidationError(ref e) => CommandError::ValidationError(e.clone()),
+            CommandError::IncompleteCommandError => CommandError::IncompleteCommandError,
+            CommandError::CommandNotFoundOrSupported => CommandError::CommandNotFoundOrSupported,
+            CommandError::CommandMalformed => CommandError::CommandMalformed,
+            CommandError::UTF8SliceError(e) => CommandError::UTF8SliceError(e),
+            CommandError::UTF8StringError(ref e) => CommandError::UTF8StringError(e.clone()),
+            CommandError::PayloadLengthParseError(ref e) => CommandError::PayloadLengthParseError(e.clone()),
+            CommandError::GenericError(ref s) => CommandError::GenericError(s.clone()),
+        }
+    }
+}
+
 from_error!(json::Error, CommandError, CommandError::JsonError);
 from_error!(ArgumentValidationError, CommandError, CommandError::ValidationError);
 from_error!(::std::str::Utf8Error, CommandError, CommandError::UTF8SliceError);
@@ -49,7 +67,7 @@ from_error!(
 from_error!(String, CommandError, CommandError::GenericError);
 
 /// This error is designed to be given when an argument like the `subject` or `queue_group` arguments are
-/// containing spaces or tabs, which is prohibited by the protocol and trigger an error server-side
+/// containing spaces, tabs or control characters, which is prohibited by the protocol and trigger an error server-side
 #[derive(Debug, Clone, Eq, PartialEq, Fail)]
 pub enum ArgumentValidationError {
     /// The argument contains spaces
@@ -58,4 +76,7 @@ pub enum ArgumentValidationError {
     /// The argument contains tabs
     #[fail(display = "The argument contains tabs")]
     ContainsTab,
+    /// The argument contains control characters, such as CR or LF, that would break the command framing
+    #[fail(display = "The argument contains control characters")]
+    ContainsControlChar,
 }
@@ -1,4 +1,5 @@
 use bytes::Bytes;
+use error::NatsError;
 
 /// Trait used to implement a common interface for implementing new commands
 pub trait Command {
@@ -17,6 +18,18 @@ pub(crate) fn check_command_arg(s: &str) -> Result<(), ArgumentValidationError>
         return Err(ArgumentValidationError::ContainsSpace);
     } else if s.contains('\t') {
         return Err(ArgumentValidationError::ContainsTab);
+    } else if s.chars().any(char::is_control) {
+        return Err(ArgumentValidationError::ContainsControlChar);
+    }
+
+    Ok(())
+}
+
+/// Checks that a subject (or reply inbox) is a single protocol token, as commands built by hand skip the
+/// builders' validation
+pub(crate) fn check_subject(subject: &str) -> Result<(), NatsError> {
+    if subject.is_empty() || check_command_arg(subject).is_err() {
+        return Err(NatsError::InvalidSubject(subject.into()));
     }
 
     Ok(())
@@ -34,6 +47,9 @@ macro_rules! check_cmd_arg {
             Err(ArgumentValidationError::ContainsTab) => {
                 return Err(format!("{} contains tabs", $part).into());
             }
+            Err(ArgumentValidationError::ContainsControlChar) => {
+                return Err(format!("{} contains control characters", $part).into());
+            }
         }
     };
 }
@@ -57,7 +73,8 @@ pub mod commands {
 
 #[cfg(test)]
 mod tests {
-    use super::check_command_arg;
+    use super::{check_command_arg, check_subject};
+    use error::NatsError;
 
     #[test]
     #[should_panic]
@@ -71,8 +88,29 @@ mod tests {
         check_command_arg(&"foo\tbar").unwrap()
     }
 
+    #[test]
+    #[should_panic]
+    fn it_detects_control_chars() {
+        check_command_arg(&"foo\r\nbar").unwrap()
+    }
+
     #[test]
     fn it_works() {
         check_command_arg(&"foo.bar").unwrap()
     }
+
+    #[test]
+    fn it_rejects_subjects_with_newlines() {
+        match check_subject("foo\nbar") {
+            Err(NatsError::InvalidSubject(subject)) => assert_eq!(subject, "foo\nbar"),
+            other => panic!("Expected an InvalidSubject error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_accepts_normal_subjects() {
+        for subject in &["foo", "foo.bar", "foo.*.baz", "foo.>", "_INBOX.d3adb33f", "ünïcödé"] {
+            check_subject(subject).unwrap();
+        }
+    }
 }
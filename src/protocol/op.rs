@@ -14,7 +14,7 @@ pub enum Op {
     SUB(SubCommand),
     /// **CLIENT** Unsubscribe (or auto-unsubscribe) from subject
     UNSUB(UnsubCommand),
-    /// **SERVER** Delivers a message payload to a subscriber
+    /// **SERVER** Delivers a message payload to a subscriber, along with its headers if sent as HMSG
     MSG(Message),
     /// **BOTH** PING keep-alive message
     PING,
@@ -61,6 +61,7 @@ impl Op {
             ServerInfo::CMD_NAME => op_from_cmd!(buf, ServerInfo::try_parse, Op::INFO),
             ConnectCommand::CMD_NAME => op_from_cmd!(buf, ConnectCommand::try_parse, Op::CONNECT),
             Message::CMD_NAME => op_from_cmd!(buf, Message::try_parse, Op::MSG),
+            Message::HMSG_CMD_NAME => op_from_cmd!(buf, Message::try_parse_with_headers, Op::MSG),
             PubCommand::CMD_NAME => op_from_cmd!(buf, PubCommand::try_parse, Op::PUB),
             SubCommand::CMD_NAME => op_from_cmd!(buf, SubCommand::try_parse, Op::SUB),
             UnsubCommand::CMD_NAME => op_from_cmd!(buf, UnsubCommand::try_parse, Op::UNSUB),
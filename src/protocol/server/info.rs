@@ -56,6 +56,11 @@ pub struct ServerInfo {
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) connect_urls: Option<Vec<String>>,
+    /// If this is set, the client must sign it for NKEY/creds authentication. A new nonce is generated for every
+    /// connection.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) nonce: Option<String>,
 }
 
 impl ServerInfo {
@@ -14,15 +14,122 @@ pub struct Message {
     /// The inbox subject on which the publisher is listening for responses
     #[builder(default)]
     pub reply_to: Option<String>,
+    /// Raw header block of messages delivered through HMSG, starting with the `NATS/1.0` status line
+    #[builder(default)]
+    pub headers: Option<Bytes>,
     /// The message payload data
     #[builder(setter(into))]
     pub payload: Bytes,
 }
 
 impl Message {
+    /// Command name of messages delivered with headers
+    pub const HMSG_CMD_NAME: &'static [u8] = b"HMSG";
+
     pub fn builder() -> MessageBuilder {
         MessageBuilder::default()
     }
+
+    /// Tells if this message is a JetStream control message (idle heartbeat or flow control request). Those carry
+    /// a `100` status in their headers and no user data
+    pub fn is_jetstream_control(&self) -> bool {
+        match self.headers {
+            Some(ref headers) => headers.starts_with(b"NATS/1.0 100"),
+            None => false,
+        }
+    }
+
+    /// Value of the header `name`, compared case-insensitively, if this message has headers and carries it
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let headers = ::std::str::from_utf8(self.headers.as_ref()?).ok()?;
+        headers
+            .split("\r\n")
+            // Skips the status line
+            .skip(1)
+            .filter_map(|line| {
+                let mut key_value = line.splitn(2, ':');
+                Some((key_value.next()?, key_value.next()?))
+            }).find(|&(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    /// Subject on which a JetStream control message expects an empty reply for delivery to go on: the reply subject
+    /// of flow control requests, or the one given in the `Nats-Consumer-Stalled` header of the idle heartbeats sent
+    /// while the consumer is stalled. `None` for plain heartbeats and regular messages
+    pub fn flow_control_reply(&self) -> Option<&str> {
+        if !self.is_jetstream_control() {
+            return None;
+        }
+
+        match self.reply_to {
+            Some(ref reply_to) => Some(reply_to),
+            None => self.header("Nats-Consumer-Stalled"),
+        }
+    }
+
+    /// Tries to parse a HMSG command. Since headers contain CRLFs, the declared lengths are used to delimit them
+    pub fn try_parse_with_headers(buf: &[u8]) -> Result<Self, CommandError> {
+        let len = buf.len();
+
+        if len < 2 || buf[len - 2..] != [b'\r', b'\n'] {
+            return Err(CommandError::IncompleteCommandError);
+        }
+
+        let line_end = buf
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| CommandError::IncompleteCommandError)?;
+
+        let whole_command = ::std::str::from_utf8(&buf[..line_end])?;
+        let mut split = whole_command.split_whitespace();
+        let cmd = split.next().ok_or_else(|| CommandError::CommandMalformed)?;
+        // Check if we're still on the right command
+        if cmd.as_bytes() != Self::HMSG_CMD_NAME {
+            return Err(CommandError::CommandMalformed);
+        }
+
+        let total_len: usize = split
+            .next_back()
+            .ok_or_else(|| CommandError::CommandMalformed)?
+            .parse()?;
+        let headers_len: usize = split
+            .next_back()
+            .ok_or_else(|| CommandError::CommandMalformed)?
+            .parse()?;
+
+        if headers_len > total_len {
+            return Err(CommandError::CommandMalformed);
+        }
+
+        let body_start = line_end + 2;
+        let body_end = body_start
+            .checked_add(total_len)
+            .ok_or_else(|| CommandError::CommandMalformed)?;
+        let command_len = body_end.checked_add(2).ok_or_else(|| CommandError::CommandMalformed)?;
+        if len < command_len {
+            return Err(CommandError::IncompleteCommandError);
+        } else if len != command_len {
+            return Err(CommandError::CommandMalformed);
+        }
+
+        let headers: Bytes = buf[body_start..body_start + headers_len].into();
+        let payload: Bytes = buf[body_start + headers_len..body_end].into();
+
+        // Extract subject
+        let subject: String = split.next().ok_or_else(|| CommandError::CommandMalformed)?.into();
+
+        let sid: String = split.next().ok_or_else(|| CommandError::CommandMalformed)?.into();
+
+        let reply_to: Option<String> = split.next().map(|v| v.into());
+
+        Ok(Message {
+            subject,
+            sid,
+            reply_to,
+            headers: Some(headers),
+            payload,
+        })
+    }
 }
 
 impl Command for Message {
@@ -35,6 +142,24 @@ impl Command for Message {
             "".into()
         };
 
+        if let Some(headers) = self.headers {
+            let cmd_str = format!(
+                "HMSG\t{}\t{}{}\t{}\t{}\r\n",
+                self.subject,
+                self.sid,
+                rt,
+                headers.len(),
+                headers.len() + self.payload.len()
+            );
+            let mut bytes = BytesMut::with_capacity(cmd_str.len() + headers.len() + self.payload.len() + 2);
+            bytes.put(cmd_str.as_bytes());
+            bytes.put(headers);
+            bytes.put(self.payload);
+            bytes.put("\r\n");
+
+            return Ok(bytes.freeze());
+        }
+
         let cmd_str = format!("MSG\t{}\t{}{}\t{}\r\n", self.subject, self.sid, rt, self.payload.len());
         let mut bytes = BytesMut::with_capacity(cmd_str.len() + self.payload.len() + 2);
         bytes.put(cmd_str.as_bytes());
@@ -87,6 +212,7 @@ impl Command for Message {
                 sid,
                 payload,
                 reply_to,
+                headers: None,
             })
         } else {
             Err(CommandError::CommandMalformed)
@@ -113,9 +239,13 @@ impl MessageBuilder {
 #[cfg(test)]
 mod tests {
     use super::{Message, MessageBuilder};
-    use protocol::Command;
+    use protocol::{Command, CommandError};
 
     static DEFAULT_MSG: &'static str = "MSG\tFOO\tpouet\t4\r\ntoto\r\n";
+    static FLOW_CONTROL_HMSG: &'static str =
+        "HMSG\tFOO\tpouet\t$JS.FC.bar\t36\t36\r\nNATS/1.0 100 FlowControl Request\r\n\r\n\r\n";
+    static STALLED_HEARTBEAT_HMSG: &'static str =
+        "HMSG\tFOO\tpouet\t66\t66\r\nNATS/1.0 100 Idle Heartbeat\r\nNats-Consumer-Stalled: $JS.FC.baz\r\n\r\n\r\n";
 
     #[test]
     fn it_parses() {
@@ -143,4 +273,75 @@ mod tests {
 
         assert_eq!(DEFAULT_MSG, cmd_bytes);
     }
+
+    #[test]
+    fn it_parses_with_headers() {
+        let parse_res = Message::try_parse_with_headers(FLOW_CONTROL_HMSG.as_bytes());
+        assert!(parse_res.is_ok());
+        let cmd = parse_res.unwrap();
+        assert_eq!(&cmd.subject, "FOO");
+        assert_eq!(&cmd.sid, "pouet");
+        assert_eq!(cmd.reply_to, Some("$JS.FC.bar".into()));
+        assert!(cmd.payload.is_empty());
+        assert!(cmd.is_jetstream_control());
+    }
+
+    #[test]
+    fn it_stringifies_with_headers() {
+        let cmd = MessageBuilder::default()
+            .subject("FOO")
+            .sid("pouet")
+            .reply_to(Some("$JS.FC.bar".into()))
+            .headers(Some("NATS/1.0 100 FlowControl Request\r\n\r\n".into()))
+            .payload("")
+            .build()
+            .unwrap();
+
+        let cmd_bytes = cmd.into_vec().unwrap();
+        assert_eq!(FLOW_CONTROL_HMSG, cmd_bytes);
+    }
+
+    #[test]
+    fn it_detects_jetstream_control() {
+        let cmd = Message::try_parse(DEFAULT_MSG.as_bytes()).unwrap();
+        assert!(!cmd.is_jetstream_control());
+
+        let cmd = MessageBuilder::default()
+            .subject("FOO")
+            .sid("pouet")
+            .headers(Some("NATS/1.0\r\nfoo: bar\r\n\r\n".into()))
+            .payload("toto")
+            .build()
+            .unwrap();
+        assert!(!cmd.is_jetstream_control());
+    }
+
+    #[test]
+    fn it_finds_the_flow_control_reply() {
+        let cmd = Message::try_parse_with_headers(FLOW_CONTROL_HMSG.as_bytes()).unwrap();
+        assert_eq!(cmd.flow_control_reply(), Some("$JS.FC.bar"));
+
+        let cmd = Message::try_parse_with_headers(STALLED_HEARTBEAT_HMSG.as_bytes()).unwrap();
+        assert!(cmd.reply_to.is_none());
+        assert_eq!(cmd.header("nats-consumer-stalled"), Some("$JS.FC.baz"));
+        assert_eq!(cmd.flow_control_reply(), Some("$JS.FC.baz"));
+
+        let cmd = MessageBuilder::default()
+            .subject("FOO")
+            .sid("pouet")
+            .headers(Some("NATS/1.0 100 Idle Heartbeat\r\n\r\n".into()))
+            .payload("")
+            .build()
+            .unwrap();
+        assert!(cmd.flow_control_reply().is_none());
+    }
+
+    #[test]
+    fn it_rejects_overflowing_lengths() {
+        let hmsg = format!("HMSG\tFOO\tpouet\t0\t{}\r\n\r\n", ::std::usize::MAX);
+        match Message::try_parse_with_headers(hmsg.as_bytes()) {
+            Err(CommandError::CommandMalformed) => {}
+            other => panic!("Expected CommandMalformed, got {:?}", other),
+        }
+    }
 }
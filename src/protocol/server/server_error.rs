@@ -1,3 +1,4 @@
+use error::NatsError;
 use std::fmt;
 
 /// The -ERR message is used by the server indicate a protocol, authorization, or other runtime
@@ -6,14 +7,75 @@ use std::fmt;
 /// Handling of these errors usually has to be done asynchronously.
 #[derive(Debug, PartialEq, Clone)]
 pub struct ServerError(String);
+
+impl ServerError {
+    /// Tells if the server refused the connection because it reached its maximum number of connections
+    pub fn is_max_connections_exceeded(&self) -> bool {
+        self.0.to_lowercase().contains("maximum connections exceeded")
+    }
+
+    /// Text of the error, without the command name and the trailing CRLF kept by the parser
+    fn message(&self) -> &str {
+        self.0.trim_start_matches("ERR").trim()
+    }
+}
+
 impl From<String> for ServerError {
     fn from(s: String) -> Self {
         ServerError(s)
     }
 }
 
+/// Refusals from a full server keep their own variant, every other -ERR is reported with its message
+impl From<ServerError> for NatsError {
+    fn from(err: ServerError) -> Self {
+        if err.is_max_connections_exceeded() {
+            NatsError::MaxConnectionsExceeded
+        } else {
+            NatsError::ServerError(err.message().to_string())
+        }
+    }
+}
+
 impl fmt::Display for ServerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_tuple("ServerError").field(&self.0).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ServerError;
+    use error::NatsError;
+    use protocol::Op;
+
+    #[test]
+    fn it_detects_max_connections_exceeded() {
+        match Op::from_bytes(b"-ERR", b"-ERR 'Maximum Connections Exceeded'\r\n").unwrap() {
+            Op::ERR(err) => assert!(err.is_max_connections_exceeded()),
+            op => panic!("Expected an ERR, got {:?}", op),
+        }
+
+        match Op::from_bytes(b"-ERR", b"-ERR 'Authorization Violation'\r\n").unwrap() {
+            Op::ERR(err) => assert!(!err.is_max_connections_exceeded()),
+            op => panic!("Expected an ERR, got {:?}", op),
+        }
+    }
+
+    #[test]
+    fn it_converts_to_nats_error() {
+        let full: NatsError = ServerError::from("Maximum Connections Exceeded".to_string()).into();
+        assert!(match full {
+            NatsError::MaxConnectionsExceeded => true,
+            _ => false,
+        });
+
+        match Op::from_bytes(b"-ERR", b"-ERR 'Authorization Violation'\r\n").unwrap() {
+            Op::ERR(err) => assert!(match NatsError::from(err) {
+                NatsError::ServerError(ref msg) => msg == "'Authorization Violation'",
+                _ => false,
+            }),
+            op => panic!("Expected an ERR, got {:?}", op),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use futures::prelude::*;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+use error::NatsError;
+use protocol::Op;
+
+/// Limits applied to the outbound publishes. Each limit allows bursts of up to one second worth of traffic
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NatsRateLimit {
+    /// Maximum number of published messages per second. Must be greater than 0, `None` means unlimited
+    pub msgs_per_sec: Option<u32>,
+    /// Maximum number of published payload bytes per second. Must be greater than 0, `None` means unlimited
+    pub bytes_per_sec: Option<u32>,
+}
+
+/// Classic token bucket, refilled continuously at `rate` tokens per second
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last_refill {
+            let elapsed = now.duration_since(self.last_refill);
+            let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            self.tokens = (self.tokens + elapsed_secs * self.rate).min(self.rate);
+            self.last_refill = now;
+        }
+    }
+
+    /// Time to wait until `amount` tokens are available. Amounts bigger than the bucket are clamped to its size
+    /// so that they can go through eventually
+    fn wait_for(&mut self, amount: f64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        let missing = amount.min(self.rate) - self.tokens;
+        if missing <= 0.0 {
+            None
+        } else {
+            let secs = missing / self.rate;
+            // Rounded up so that the tokens are actually there once the wait is over
+            Some(Duration::new(secs as u64, (secs.fract() * 1e9).ceil().max(1.0) as u32))
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount.min(self.rate);
+    }
+}
+
+/// Rate limiter of the publish path
+#[derive(Debug, Clone)]
+pub(crate) struct PublishRateLimiter {
+    msgs: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl PublishRateLimiter {
+    pub(crate) fn new(limit: NatsRateLimit, now: Instant) -> Self {
+        PublishRateLimiter {
+            msgs: limit.msgs_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            bytes: limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Tries to publish a message of `payload_len` bytes. Either consumes the budget for it, or returns how long
+    /// to wait before trying again without consuming anything
+    pub(crate) fn try_acquire(&mut self, payload_len: usize, now: Instant) -> Result<(), Duration> {
+        let msgs_wait = self.msgs.as_mut().and_then(|bucket| bucket.wait_for(1.0, now));
+        let bytes_wait = self
+            .bytes
+            .as_mut()
+            .and_then(|bucket| bucket.wait_for(payload_len as f64, now));
+
+        match (msgs_wait, bytes_wait) {
+            (None, None) => {
+                if let Some(ref mut bucket) = self.msgs {
+                    bucket.take(1.0);
+                }
+                if let Some(ref mut bucket) = self.bytes {
+                    bucket.take(payload_len as f64);
+                }
+                Ok(())
+            }
+            (Some(wait), None) | (None, Some(wait)) => Err(wait),
+            (Some(msgs_wait), Some(bytes_wait)) => Err(msgs_wait.max(bytes_wait)),
+        }
+    }
+}
+
+/// Queue of the OPs to write, holding each PUB back until the budget allows it. Everything queued after a PUB
+/// that is held back waits for it, so the OPs are written in the order they were sent
+#[derive(Debug)]
+pub(crate) struct ThrottledOps<S> {
+    ops: S,
+    limiter: PublishRateLimiter,
+    held: Option<Op>,
+    delay: Option<Delay>,
+}
+
+impl<S> ThrottledOps<S> {
+    pub(crate) fn new(ops: S, limit: NatsRateLimit, now: Instant) -> Self {
+        ThrottledOps {
+            ops,
+            limiter: PublishRateLimiter::new(limit, now),
+            held: None,
+            delay: None,
+        }
+    }
+}
+
+impl<S> Stream for ThrottledOps<S>
+where
+    S: Stream<Item = Op, Error = NatsError>,
+{
+    type Error = NatsError;
+    type Item = Op;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                if delay
+                    .poll()
+                    .map_err(|e| NatsError::GenericError(e.to_string()))?
+                    .is_not_ready()
+                {
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.delay = None;
+
+            let op = match self.held.take() {
+                Some(op) => op,
+                None => match self.ops.poll()? {
+                    Async::Ready(Some(op)) => op,
+                    not_op => return Ok(not_op),
+                },
+            };
+
+            let payload_len = match op {
+                Op::PUB(ref cmd) => cmd.payload.len(),
+                op => return Ok(Async::Ready(Some(op))),
+            };
+
+            let now = Instant::now();
+            match self.limiter.try_acquire(payload_len, now) {
+                Ok(()) => return Ok(Async::Ready(Some(op))),
+                Err(wait) => {
+                    debug!(target: "nitox", "Publish rate limit reached, waiting {:?}", wait);
+                    self.held = Some(op);
+                    self.delay = Some(Delay::new(now + wait));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NatsRateLimit, PublishRateLimiter, ThrottledOps};
+    use error::NatsError;
+    use futures::{prelude::*, stream};
+    use protocol::{commands::*, Op};
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Publishes `count` messages, advancing the mock clock by the waits requested by the limiter
+    fn publish_with_mock_clock(limiter: &mut PublishRateLimiter, count: usize, payload_len: usize) -> Duration {
+        let start = Instant::now();
+        let mut now = start;
+        let mut published = 0;
+        while published < count {
+            match limiter.try_acquire(payload_len, now) {
+                Ok(()) => published += 1,
+                Err(wait) => now += wait,
+            }
+        }
+
+        now.duration_since(start)
+    }
+
+    #[test]
+    fn it_paces_messages() {
+        let limit = NatsRateLimit {
+            msgs_per_sec: Some(10),
+            bytes_per_sec: None,
+        };
+        let mut limiter = PublishRateLimiter::new(limit, Instant::now());
+
+        // The first 10 messages are the allowed burst, the 40 others are paced at 10 msgs/sec
+        let elapsed = publish_with_mock_clock(&mut limiter, 50, 0);
+        assert!(elapsed >= Duration::from_millis(3_990), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(4_010), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn it_paces_bytes() {
+        let limit = NatsRateLimit {
+            msgs_per_sec: None,
+            bytes_per_sec: Some(1_000),
+        };
+        let mut limiter = PublishRateLimiter::new(limit, Instant::now());
+
+        let elapsed = publish_with_mock_clock(&mut limiter, 30, 100);
+        assert!(elapsed >= Duration::from_millis(1_990), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(2_010), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn it_does_not_consume_budget_when_waiting() {
+        let limit = NatsRateLimit {
+            msgs_per_sec: Some(1),
+            bytes_per_sec: None,
+        };
+        let now = Instant::now();
+        let mut limiter = PublishRateLimiter::new(limit, now);
+
+        assert!(limiter.try_acquire(0, now).is_ok());
+        assert!(limiter.try_acquire(0, now).is_err());
+        assert!(limiter.try_acquire(0, now + Duration::from_millis(500)).is_err());
+        assert!(limiter.try_acquire(0, now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn it_keeps_the_order_of_throttled_ops() {
+        let limit = NatsRateLimit {
+            msgs_per_sec: Some(10),
+            bytes_per_sec: None,
+        };
+        let publish = |i: usize| {
+            Op::PUB(
+                PubCommand::builder()
+                    .subject(format!("ordered.{}", i))
+                    .payload("")
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        // The 11th PUB is the first one held back, the SUB queued after it must not overtake it
+        let mut ops: Vec<Op> = (0..11).map(publish).collect();
+        ops.push(Op::SUB(SubCommand::builder().subject("ordered.*").build().unwrap()));
+        ops.push(publish(11));
+
+        let start = Instant::now();
+        let written = Runtime::new()
+            .unwrap()
+            .block_on(ThrottledOps::new(stream::iter_ok::<_, NatsError>(ops.clone()), limit, start).collect())
+            .unwrap();
+
+        assert_eq!(written, ops);
+        assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+    }
+}
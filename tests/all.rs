@@ -14,8 +14,15 @@ use futures::{
     prelude::*,
     sync::{mpsc, oneshot},
 };
-use nitox::{codec::OpCodec, commands::*, NatsClient, NatsClientOptions, NatsError, Op};
+use nitox::{
+    codec::OpCodec, commands::*, LazyNatsClient, NatsBackoff, NatsClient, NatsClientOptions, NatsError, NatsEvent, Op,
+};
 use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio_codec::Decoder;
 use tokio_tcp::TcpListener;
 
@@ -25,6 +32,33 @@ macro_rules! elog {
     };
 }
 
+/// Tells if `subject` matches the (maybe wildcarded) subscription `pattern`
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (t, Some(s)) if t == s => {}
+            _ => return false,
+        }
+    }
+
+    subject_tokens.next().is_none()
+}
+
+fn mock_server_info() -> ServerInfo {
+    ServerInfo::builder()
+        .server_id("nitox-nats")
+        .version(::std::env::var("CARGO_PKG_VERSION").unwrap())
+        .go("lol")
+        .host("127.0.0.1")
+        .port(4222u32)
+        .max_payload(::std::u32::MAX)
+        .build()
+        .unwrap()
+}
+
 fn create_tcp_mock(
     runtime: &mut tokio::runtime::Runtime,
     port: usize,
@@ -33,85 +67,125 @@ fn create_tcp_mock(
     let verbose = is_verbose.unwrap_or(false);
     let listener = TcpListener::bind(&format!("127.0.0.1:{}", port).parse()?)?;
     debug!(target: "nitox", "TCP Mock NATS Server started on port {}", port);
-    runtime.spawn(
-        listener
-            .incoming()
-            .map(move |socket| OpCodec::default().framed(socket))
-            .from_err()
-            .and_then(|socket| {
-                socket.send(Op::INFO(
-                    ServerInfo::builder()
-                        .server_id("nitox-nats")
-                        .version(::std::env::var("CARGO_PKG_VERSION").unwrap())
-                        .go("lol")
-                        .host("127.0.0.1")
-                        .port(4222u32)
-                        .max_payload(::std::u32::MAX)
-                        .build()
-                        .unwrap(),
-                ))
-            }).and_then(|socket| socket.send(Op::PING))
-            .and_then(move |socket| {
-                let (sink, stream) = socket.split();
-                let (tx, rx) = mpsc::unbounded();
-                let rx = rx.map_err(|_| NatsError::InnerBrokenChain);
-                tokio_executor::spawn(sink.send_all(rx).map(|_| ()).map_err(|_| ()));
-
-                let sid_lock = RwLock::new(String::new());
-
-                stream.for_each(move |op| {
-                    debug!(target: "nitox", "Got OP from client {:#?}", op);
-                    match op {
-                        Op::PONG => {
-                            debug!(target: "nitox", "Got PONG from client");
-                            if verbose {
-                                let _ = tx.unbounded_send(Op::OK);
+    // Each client gets served by its own task, so that the listener outlives the connections
+    runtime.spawn(listener.incoming().map_err(|_| ()).for_each(move |socket| {
+        tokio_executor::spawn(
+            OpCodec::default()
+                .framed(socket)
+                .send(Op::INFO(mock_server_info()))
+                .and_then(|socket| socket.send(Op::PING))
+                .and_then(move |socket| {
+                    let (sink, stream) = socket.split();
+                    let (tx, rx) = mpsc::unbounded();
+                    let rx = rx.map_err(|_| NatsError::InnerBrokenChain);
+                    tokio_executor::spawn(sink.send_all(rx).map(|_| ()).map_err(|_| ()));
+
+                    let subs_lock: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+                    // Like a real server, messages with headers are only sent to clients asking for them in CONNECT
+                    let headers_lock = RwLock::new(false);
+
+                    stream.for_each(move |op| {
+                        debug!(target: "nitox", "Got OP from client {:#?}", op);
+                        match op {
+                            Op::PONG => {
+                                debug!(target: "nitox", "Got PONG from client");
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
                             }
-                        }
-                        Op::PING => {
-                            if verbose {
-                                let _ = tx.unbounded_send(Op::OK);
+                            Op::PING => {
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
+                                let _ = tx.unbounded_send(Op::PONG);
                             }
-                            let _ = tx.unbounded_send(Op::PONG);
-                        }
-                        Op::SUB(cmd) => {
-                            if verbose {
-                                let _ = tx.unbounded_send(Op::OK);
+                            Op::SUB(cmd) => {
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
+
+                                subs_lock.write().insert(cmd.sid, cmd.subject);
                             }
+                            Op::UNSUB(cmd) => {
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
 
-                            *sid_lock.write() = cmd.sid;
-                        }
-                        Op::PUB(cmd) => {
-                            debug!(target: "nitox", "Got PUB command {:#?}", cmd);
-                            if verbose {
-                                let _ = tx.unbounded_send(Op::OK);
+                                if cmd.max_msgs.is_none() {
+                                    subs_lock.write().remove(&cmd.sid);
+                                }
                             }
-                            let mut builder = Message::builder();
-                            let sub = cmd.subject.clone();
-                            builder.subject(cmd.reply_to.unwrap_or(sub));
-                            {
-                                let sid = sid_lock.read();
-                                builder.sid((*sid).clone());
+                            Op::CONNECT(cmd) => {
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
+
+                                *headers_lock.write() = cmd.headers == Some(true);
                             }
-                            builder.payload("bar");
+                            Op::PUB(cmd) => {
+                                debug!(target: "nitox", "Got PUB command {:#?}", cmd);
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
+                                let target = cmd.reply_to.unwrap_or(cmd.subject);
+                                for (sid, subject) in subs_lock.read().iter() {
+                                    if !subject_matches(subject, &target) {
+                                        continue;
+                                    }
 
-                            let msg = builder.build().unwrap();
-                            debug!(target: "nitox", "Replying with MSG command {:#?}", msg);
-                            let _ = tx.unbounded_send(Op::MSG(msg));
-                        }
-                        _ => {
-                            if verbose {
-                                let _ = tx.unbounded_send(Op::OK);
+                                    // Simulates a JetStream push consumer asking for flow control before delivering
+                                    if target.starts_with("fc.") && *headers_lock.read() {
+                                        let control = Message::builder()
+                                            .subject(target.clone())
+                                            .sid(sid.clone())
+                                            .reply_to(Some(format!("fc-ack.{}", target)))
+                                            .headers(Some("NATS/1.0 100 FlowControl Request\r\n\r\n".into()))
+                                            .payload("")
+                                            .build()
+                                            .unwrap();
+                                        let _ = tx.unbounded_send(Op::MSG(control));
+                                    }
+
+                                    // Simulates a stalled JetStream consumer, telling where to reply in a heartbeat
+                                    if target.starts_with("stalled.") && *headers_lock.read() {
+                                        let headers = format!(
+                                            "NATS/1.0 100 Idle Heartbeat\r\nNats-Consumer-Stalled: fc-ack.{}\r\n\r\n",
+                                            target
+                                        );
+                                        let heartbeat = Message::builder()
+                                            .subject(target.clone())
+                                            .sid(sid.clone())
+                                            .headers(Some(headers.into()))
+                                            .payload("")
+                                            .build()
+                                            .unwrap();
+                                        let _ = tx.unbounded_send(Op::MSG(heartbeat));
+                                    }
+
+                                    let msg = Message::builder()
+                                        .subject(target.clone())
+                                        .sid(sid.clone())
+                                        .payload("bar")
+                                        .build()
+                                        .unwrap();
+                                    debug!(target: "nitox", "Replying with MSG command {:#?}", msg);
+                                    let _ = tx.unbounded_send(Op::MSG(msg));
+                                }
+                            }
+                            _ => {
+                                if verbose {
+                                    let _ = tx.unbounded_send(Op::OK);
+                                }
                             }
                         }
-                    }
 
-                    future::ok(())
-                })
-            }).into_future()
-            .map(|_| ())
-            .map_err(|_| ()),
-    );
+                        future::ok(())
+                    })
+                }).map_err(|_| ()),
+        );
+
+        Ok(())
+    }));
 
     Ok(())
 }
@@ -425,3 +499,909 @@ fn can_pong_to_ping() {
     debug!(target: "nitox", "can_pong_to_ping::connection_result {:#?}", connection_result);
     assert!(connection_result.is_ok());
 }
+
+#[test]
+fn can_tap_readonly_stream() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1340, None);
+    debug!(target: "nitox", "can_tap_readonly_stream::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1340")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let readonly = client.subscribe_readonly();
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                .and_then(move |_| {
+                    readonly
+                        .skip_while(|op| match op {
+                            Op::MSG(_) => future::ok(false),
+                            _ => future::ok(true),
+                        }).into_future()
+                        .map(|(op, _)| op.unwrap())
+                        .map_err(|(e, _)| e)
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_tap_readonly_stream::connection_result {:#?}", connection_result);
+    match connection_result {
+        Ok(Op::MSG(msg)) => assert_eq!(msg.payload, "bar"),
+        other => panic!("Expected a MSG on the read-only stream, got {:?}", other),
+    }
+}
+
+#[test]
+fn can_change_subscription_subject() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1341, None);
+    debug!(target: "nitox", "can_change_subscription_subject::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1341")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_with_handle(SubCommand::builder().subject("a.*").build().unwrap())
+                .and_then(move |(subscription, stream)| {
+                    let _ = client
+                        .publish(PubCommand::builder().subject("a.1").build().unwrap())
+                        .wait();
+
+                    stream
+                        .into_future()
+                        .map_err(|(e, _)| e)
+                        .and_then(move |(first, stream)| {
+                            subscription
+                                .change_subject("b.*".into())
+                                .and_then(move |_| {
+                                    let _ = client
+                                        .publish(PubCommand::builder().subject("a.2").build().unwrap())
+                                        .wait();
+                                    client.publish(PubCommand::builder().subject("b.1").build().unwrap())
+                                }).and_then(move |_| stream.into_future().map_err(|(e, _)| e))
+                                .map(move |(second, _)| (first.unwrap().subject, second.unwrap().subject))
+                        })
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_change_subscription_subject::connection_result {:#?}", connection_result);
+    let (first, second) = connection_result.unwrap();
+    assert_eq!(first, "a.1");
+    assert_eq!(second, "b.1");
+}
+
+#[test]
+fn can_subscribe_all_at_once() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1342, None);
+    debug!(target: "nitox", "can_subscribe_all_at_once::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1342")
+        .build()
+        .unwrap();
+
+    let sub_cmds: Vec<SubCommand> = (0..500)
+        .map(|i| SubCommand::builder().subject(format!("eager.{}", i)).build().unwrap())
+        .collect();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client.subscribe_all(sub_cmds).and_then(move |mut streams| {
+                assert_eq!(streams.len(), 500);
+                let _ = client
+                    .publish(PubCommand::builder().subject("eager.499").build().unwrap())
+                    .wait();
+
+                streams
+                    .pop()
+                    .unwrap()
+                    .into_future()
+                    .map(|(maybe_message, _)| maybe_message.unwrap())
+                    .map_err(|(e, _)| e)
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_subscribe_all_at_once::connection_result {:#?}", connection_result);
+    assert_eq!(connection_result.unwrap().subject, "eager.499");
+}
+
+#[test]
+fn can_reply_to_flow_control() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1343, None);
+    debug!(target: "nitox", "can_reply_to_flow_control::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1343")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_all(vec![
+                    SubCommand::builder().subject("fc.data").build().unwrap(),
+                    SubCommand::builder().subject("fc-ack.>").build().unwrap(),
+                ]).and_then(move |mut streams| {
+                    let acks = streams.pop().unwrap();
+                    let data = streams.pop().unwrap();
+                    let _ = client
+                        .publish(PubCommand::builder().subject("fc.data").build().unwrap())
+                        .wait();
+
+                    data.into_future()
+                        .map_err(|(e, _)| e)
+                        .join(acks.into_future().map_err(|(e, _)| e))
+                        .map(|((data_msg, _), (ack_msg, _))| (data_msg.unwrap(), ack_msg.unwrap()))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_reply_to_flow_control::connection_result {:#?}", connection_result);
+    let (data_msg, ack_msg) = connection_result.unwrap();
+    // The control message is swallowed and delivery continues with the actual data
+    assert!(data_msg.headers.is_none());
+    assert_eq!(data_msg.payload, "bar");
+    assert_eq!(ack_msg.subject, "fc-ack.fc.data");
+}
+
+#[test]
+fn can_reply_to_stalled_heartbeats() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1351, None);
+    debug!(target: "nitox", "can_reply_to_stalled_heartbeats::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1351")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_all(vec![
+                    SubCommand::builder().subject("stalled.data").build().unwrap(),
+                    SubCommand::builder().subject("fc-ack.>").build().unwrap(),
+                ]).and_then(move |mut streams| {
+                    let acks = streams.pop().unwrap();
+                    let data = streams.pop().unwrap();
+                    let _ = client
+                        .publish(PubCommand::builder().subject("stalled.data").build().unwrap())
+                        .wait();
+
+                    data.into_future()
+                        .map_err(|(e, _)| e)
+                        .join(acks.into_future().map_err(|(e, _)| e))
+                        .map(|((data_msg, _), (ack_msg, _))| (data_msg.unwrap(), ack_msg.unwrap()))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_reply_to_stalled_heartbeats::connection_result {:#?}", connection_result);
+    let (data_msg, ack_msg) = connection_result.unwrap();
+    // The heartbeat is swallowed and delivery continues with the actual data
+    assert!(data_msg.headers.is_none());
+    assert_eq!(data_msg.payload, "bar");
+    assert_eq!(ack_msg.subject, "fc-ack.stalled.data");
+}
+
+#[test]
+fn has_no_tls_info_over_plaintext() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1344, None);
+    debug!(target: "nitox", "has_no_tls_info_over_plaintext::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1344")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options).and_then(|client| client.connect());
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "has_no_tls_info_over_plaintext::connection_result {:#?}", connection_result);
+    assert!(connection_result.unwrap().tls_info().is_none());
+}
+
+#[test]
+fn can_connect_from_independent_runtimes() {
+    elog!();
+    let mut server_runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut server_runtime, 1345, None);
+    debug!(target: "nitox", "can_connect_from_independent_runtimes::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1345")
+        .build()
+        .unwrap();
+
+    let workers: Vec<_> = (0..2)
+        .map(|worker| {
+            // Each worker owns its own client, which only connects once used from within the worker's runtime
+            let lazy_client = LazyNatsClient::new(options.clone());
+            ::std::thread::spawn(move || {
+                let mut runtime = tokio::runtime::Runtime::new().unwrap();
+                let subject = format!("worker.{}", worker);
+                let fut = lazy_client.client().and_then(move |client| {
+                    client
+                        .subscribe(SubCommand::builder().subject(subject.clone()).build().unwrap())
+                        .and_then(move |stream| {
+                            client
+                                .publish(PubCommand::builder().subject(subject).payload("bar").build().unwrap())
+                                .and_then(move |_| stream.take(1).into_future().map_err(|(e, _)| e))
+                                .map(|(maybe_message, _)| maybe_message.unwrap())
+                        })
+                });
+
+                let (tx, rx) = oneshot::channel();
+                runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+                let result = rx.wait().expect("Cannot wait for a result");
+                let _ = runtime.shutdown_now().wait();
+                result
+            })
+        }).collect();
+
+    let messages: Vec<Message> = workers
+        .into_iter()
+        .map(|worker| worker.join().expect("Worker panicked").expect("Worker failed"))
+        .collect();
+    let _ = server_runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_connect_from_independent_runtimes::messages {:#?}", messages);
+    assert_eq!(messages[0].subject, "worker.0");
+    assert_eq!(messages[1].subject, "worker.1");
+}
+
+#[test]
+fn lazy_client_keeps_connection_errors() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    // Nothing listens on this port
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1352")
+        .build()
+        .unwrap();
+
+    let lazy_client = LazyNatsClient::new(options);
+    for _ in 0..2 {
+        let (tx, rx) = oneshot::channel();
+        runtime.spawn(
+            lazy_client
+                .client()
+                .then(|r| tx.send(r.map(|_| ())).map_err(|e| panic!("Cannot send Result {:?}", e))),
+        );
+        let connection_result = rx.wait().expect("Cannot wait for a result");
+        debug!(target: "nitox", "lazy_client_keeps_connection_errors::connection_result {:#?}", connection_result);
+        // The failed attempt is forgotten, so the second use tries again and fails the same way
+        match connection_result {
+            Err(NatsError::ServerDisconnected(Some(ref e))) if e.kind() == ::std::io::ErrorKind::ConnectionRefused => {}
+            other => panic!("Expected a refused connection, got {:?}", other),
+        }
+    }
+
+    let _ = runtime.shutdown_now().wait();
+}
+
+#[test]
+fn can_wait_for_matching_message() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1346, None);
+    debug!(target: "nitox", "can_wait_for_matching_message::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1346")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let waiting = client.wait_for(
+                "status.*".into(),
+                |msg| msg.subject == "status.ready",
+                ::std::time::Duration::from_secs(5),
+            );
+
+            // The waiter is polled first, so its SUB is queued before these PUBs
+            let publishes = future::join_all(
+                vec!["status.starting", "status.warming", "status.ready", "status.stopping"]
+                    .into_iter()
+                    .map(|subject| client.publish(PubCommand::builder().subject(subject).build().unwrap()))
+                    .collect::<Vec<_>>(),
+            );
+
+            waiting.join(publishes).map(|(msg, _)| msg)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_wait_for_matching_message::connection_result {:#?}", connection_result);
+    assert_eq!(connection_result.unwrap().subject, "status.ready");
+}
+
+#[test]
+fn wait_for_rejects_invalid_subject() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1358, None);
+    debug!(target: "nitox", "wait_for_rejects_invalid_subject::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1358")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| client.wait_for("a\nb".into(), |_| true, ::std::time::Duration::from_secs(5)));
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "wait_for_rejects_invalid_subject::connection_result {:#?}", connection_result);
+    match connection_result {
+        Err(NatsError::InvalidSubject(_)) => {}
+        other => panic!("Expected an InvalidSubject error, got {:?}", other),
+    }
+}
+
+#[test]
+fn wait_for_unsubscribes_when_timing_out_or_dropped() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1353, None);
+    debug!(target: "nitox", "wait_for_unsubscribes_when_timing_out_or_dropped::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1353")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let ops = client.subscribe_readonly();
+            // Dropped without ever being polled
+            drop(client.wait_for("dropped.*".into(), |_| false, ::std::time::Duration::from_secs(5)));
+
+            client
+                .wait_for("status.*".into(), |_| false, ::std::time::Duration::from_millis(100))
+                .then(move |res| {
+                    let timed_out = match res {
+                        Err(NatsError::Timeout(_)) => true,
+                        _ => false,
+                    };
+
+                    client
+                        .subscribe(SubCommand::builder().subject("sentinel").build().unwrap())
+                        .and_then(move |stream| {
+                            future::join_all(
+                                vec!["dropped.foo", "status.foo", "sentinel"]
+                                    .into_iter()
+                                    .map(|subject| PubCommand::builder().subject(subject).build().unwrap())
+                                    .map(|cmd| client.publish(cmd))
+                                    .collect::<Vec<_>>(),
+                            ).map(move |_| (timed_out, stream, ops))
+                        })
+                }).and_then(|(timed_out, _stream, ops)| {
+                    // The server only routes to subscriptions it still knows about
+                    ops.filter_map(|op| match op {
+                        Op::MSG(msg) => Some(msg),
+                        _ => None,
+                    }).into_future()
+                    .map_err(|(e, _)| e)
+                    .map(move |(maybe_msg, _)| (timed_out, maybe_msg.unwrap()))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "wait_for_unsubscribes_when_timing_out_or_dropped::connection_result {:#?}", connection_result);
+    let (timed_out, first_msg) = connection_result.unwrap();
+    assert!(timed_out);
+    assert_eq!(first_msg.subject, "sentinel");
+}
+
+#[test]
+fn connect_reports_max_connections_exceeded() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:1354".parse().unwrap()).unwrap();
+    // Like a real full server, sends its INFO before refusing the connection and closing it
+    runtime.spawn(
+        listener
+            .incoming()
+            .map(move |socket| OpCodec::default().framed(socket))
+            .from_err()
+            .and_then(|socket| socket.send(Op::INFO(mock_server_info())))
+            .and_then(|socket| {
+                socket.send(Op::ERR(ServerError::from(
+                    "'Maximum Connections Exceeded'".to_string(),
+                )))
+            }).for_each(|_| future::ok::<(), NatsError>(()))
+            .map_err(|_| ()),
+    );
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1354")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .map(|_| ());
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "connect_reports_max_connections_exceeded::connection_result {:#?}", connection_result);
+    match connection_result {
+        Err(NatsError::MaxConnectionsExceeded) => {}
+        other => panic!("Expected MaxConnectionsExceeded, got {:?}", other),
+    }
+}
+
+#[test]
+fn connect_reports_server_errors() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:1355".parse().unwrap()).unwrap();
+    // Rejects the credentials of every client, keeping the connection open until the client drops it
+    runtime.spawn(
+        listener
+            .incoming()
+            .map(move |socket| OpCodec::default().framed(socket))
+            .from_err()
+            .and_then(|socket| socket.send(Op::INFO(mock_server_info())))
+            .and_then(|socket| socket.send(Op::ERR(ServerError::from("'Authorization Violation'".to_string()))))
+            .for_each(|socket| socket.for_each(|_| Ok(())))
+            .map_err(|_| ()),
+    );
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1355")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .map(|_| ());
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "connect_reports_server_errors::connection_result {:#?}", connection_result);
+    match connection_result {
+        Err(NatsError::ServerError(ref msg)) => assert!(msg.contains("Authorization Violation")),
+        other => panic!("Expected ServerError, got {:?}", other),
+    }
+}
+
+#[test]
+fn reconnection_backs_off_when_server_is_full() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:1356".parse().unwrap()).unwrap();
+    let accepted_at = Arc::new(RwLock::new(Vec::new()));
+    let accepted = Arc::clone(&accepted_at);
+    // Serves the first connection until the client asks for a reset, refuses the second one like a full server
+    // and serves the next ones
+    runtime.spawn(listener.incoming().map_err(|_| ()).for_each(move |socket| {
+        let attempt = {
+            let mut accepted = accepted.write();
+            accepted.push(Instant::now());
+            accepted.len()
+        };
+
+        let socket = OpCodec::default().framed(socket);
+        if attempt == 2 {
+            tokio_executor::spawn(
+                socket
+                    .send(Op::INFO(mock_server_info()))
+                    .and_then(|socket| {
+                        socket.send(Op::ERR(ServerError::from(
+                            "'Maximum Connections Exceeded'".to_string(),
+                        )))
+                    }).and_then(|socket| socket.for_each(|_| Ok(())))
+                    .map_err(|_| ()),
+            );
+            return Ok(());
+        }
+
+        tokio_executor::spawn(
+            socket
+                .send(Op::INFO(mock_server_info()))
+                .and_then(|socket| {
+                    future::loop_fn(socket, |socket| {
+                        socket
+                            .into_future()
+                            .map_err(|(e, _)| e)
+                            .and_then(|(maybe_op, socket)| match maybe_op {
+                                Some(Op::PING) => future::Either::A(socket.send(Op::PONG).map(future::Loop::Continue)),
+                                Some(Op::PUB(ref cmd)) if cmd.subject == "reset" => {
+                                    // Lingering for no time makes the drop reset the connection
+                                    let _ = socket.get_ref().set_linger(Some(Duration::from_secs(0)));
+                                    future::Either::B(future::ok(future::Loop::Break(())))
+                                }
+                                Some(_) => future::Either::B(future::ok(future::Loop::Continue(socket))),
+                                None => future::Either::B(future::ok(future::Loop::Break(()))),
+                            })
+                    })
+                }).map_err(|_| ()),
+        );
+        Ok(())
+    }));
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1356")
+        .max_connections_backoff(Some(NatsBackoff {
+            initial: Duration::from_millis(300),
+            max: Duration::from_secs(1),
+        })).build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let reconnected = client
+                .events()
+                .filter(|event| *event == NatsEvent::Reconnected)
+                .into_future()
+                .map_err(|(e, _)| e);
+            client
+                .publish(PubCommand::builder().subject("reset").payload("").build().unwrap())
+                .and_then(move |_| reconnected.map(move |_| client))
+        }).map(|_| ());
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "reconnection_backs_off_when_server_is_full::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+
+    let accepted_at = accepted_at.read();
+    assert_eq!(accepted_at.len(), 3);
+    assert!(accepted_at[2].duration_since(accepted_at[1]) >= Duration::from_millis(300));
+}
+
+#[test]
+fn can_receive_own_message_from_batch() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1347, None);
+    debug!(target: "nitox", "can_receive_own_message_from_batch::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().echo(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1347")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            // The PUB comes first on purpose, the batch still writes the SUB before it
+            client
+                .send_batch(vec![
+                    Op::PUB(PubCommand::builder().subject("self.echo").payload("bar").build().unwrap()),
+                    Op::SUB(SubCommand::builder().subject("self.echo").build().unwrap()),
+                ]).and_then(|mut streams| {
+                    streams
+                        .pop()
+                        .unwrap()
+                        .into_future()
+                        .map(|(maybe_message, _)| maybe_message.unwrap())
+                        .map_err(|(e, _)| e)
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_receive_own_message_from_batch::connection_result {:#?}", connection_result);
+    let msg = connection_result.unwrap();
+    assert_eq!(msg.subject, "self.echo");
+    assert_eq!(msg.payload, "bar");
+}
+
+#[test]
+fn can_flush_publish_on_close() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    // Records everything the client writes until it shuts the socket down, only answering the PING sent along
+    // with CONNECT
+    let listener = TcpListener::bind(&"127.0.0.1:1348".parse().unwrap()).unwrap();
+    let (ops_tx, ops_rx) = oneshot::channel();
+    runtime.spawn(
+        listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| NatsError::from(e))
+            .and_then(|(maybe_socket, _)| {
+                let socket = maybe_socket.expect("No incoming connection");
+                OpCodec::default().framed(socket).send(Op::INFO(mock_server_info()))
+            }).and_then(|socket| {
+                let (sink, stream) = socket.split();
+                stream
+                    .fold((sink, Vec::new()), |(sink, mut ops), op| {
+                        let answer = match op {
+                            Op::PING => future::Either::A(sink.send(Op::PONG)),
+                            _ => future::Either::B(future::ok(sink)),
+                        };
+                        ops.push(op);
+                        answer.map(move |sink| (sink, ops))
+                    }).map(|(_, ops)| ops)
+            }).then(move |ops| ops_tx.send(ops).map_err(|_| ())),
+    );
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1348")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .publish(PubCommand::builder().subject("last.words").payload("bye").build().unwrap())
+                .and_then(move |_| client.close())
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let written_ops = ops_rx.wait().expect("Cannot wait for the written OPs");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_flush_publish_on_close::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+
+    let published: Vec<PubCommand> = written_ops
+        .unwrap()
+        .into_iter()
+        .filter_map(|op| match op {
+            Op::PUB(cmd) => Some(cmd),
+            _ => None,
+        }).collect();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].subject, "last.words");
+    assert_eq!(published[0].payload, "bye");
+}
+
+#[test]
+fn subscriptions_end_on_drain() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1357, None);
+    debug!(target: "nitox", "subscriptions_end_on_drain::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1357")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("drained").build().unwrap())
+                .map(move |stream| (client, stream))
+        }).and_then(|(client, stream)| client.drain().and_then(move |_| stream.collect()));
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "subscriptions_end_on_drain::connection_result {:#?}", connection_result);
+    assert_eq!(connection_result.unwrap().len(), 0);
+}
+
+#[test]
+fn can_transform_subscription_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1349, None);
+    debug!(target: "nitox", "can_transform_subscription_messages::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1349")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_map("map.*".into(), |msg| {
+                    if msg.subject == "map.poisoned" {
+                        return Err(NatsError::GenericError("cannot transform poisoned message".into()));
+                    }
+
+                    Ok(String::from_utf8_lossy(&msg.payload).to_uppercase())
+                }).and_then(move |stream| {
+                    let publishes = future::join_all(
+                        vec!["map.first", "map.poisoned", "map.last"]
+                            .into_iter()
+                            .map(|subject| {
+                                client.publish(PubCommand::builder().subject(subject).payload("bar").build().unwrap())
+                            }).collect::<Vec<_>>(),
+                    );
+
+                    publishes.and_then(move |_| stream.take(3).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_transform_subscription_messages::connection_result {:#?}", connection_result);
+    let transformed = connection_result.unwrap();
+    assert_eq!(transformed.len(), 3);
+    assert_eq!(transformed[0].as_ref().unwrap(), "BAR");
+    match transformed[1] {
+        Err(NatsError::GenericError(ref e)) => assert_eq!(e, "cannot transform poisoned message"),
+        ref other => panic!("Expected a transformation error, got {:?}", other),
+    }
+    assert_eq!(transformed[2].as_ref().unwrap(), "BAR");
+}
+
+#[test]
+fn keeps_max_msgs_when_changing_subject() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1350, None);
+    debug!(target: "nitox", "keeps_max_msgs_when_changing_subject::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1350")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_with_handle(SubCommand::builder().subject("limited.a").build().unwrap())
+                .and_then(move |(subscription, stream)| {
+                    let limit = UnsubCommand::builder()
+                        .sid(subscription.sid())
+                        .max_msgs(Some(2))
+                        .build()
+                        .unwrap();
+                    let _ = client.unsubscribe(limit).wait();
+                    let _ = client
+                        .publish(PubCommand::builder().subject("limited.a").build().unwrap())
+                        .wait();
+
+                    stream
+                        .into_future()
+                        .map_err(|(e, _)| e)
+                        .and_then(move |(first, stream)| {
+                            subscription
+                                .change_subject("limited.b".into())
+                                .and_then(move |_| {
+                                    client.publish(PubCommand::builder().subject("limited.b").build().unwrap())
+                                }).and_then(move |_| {
+                                    stream.into_future().then(move |res| {
+                                        let second = res.map(|(msg, _)| msg).map_err(|(e, _)| e);
+                                        Ok::<_, NatsError>((first, second))
+                                    })
+                                })
+                        })
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "keeps_max_msgs_when_changing_subject::connection_result {:#?}", connection_result);
+    let (first, second) = connection_result.unwrap();
+    assert_eq!(first.unwrap().subject, "limited.a");
+    // The second message overall reaches the limit set before changing subject
+    match second {
+        Err(NatsError::SubscriptionReachedMaxMsgs(2)) => {}
+        other => panic!("Expected the subscription to reach its limit, got {:?}", other),
+    }
+}